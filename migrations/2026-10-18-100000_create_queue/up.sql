@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE "queue" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "uid" VARCHAR(255) NOT NULL UNIQUE,
+    "link" VARCHAR(255) NOT NULL,
+    "title" VARCHAR(255),
+    "channel" VARCHAR(255),
+    "position" INTEGER,
+    "status" VARCHAR(16) NOT NULL DEFAULT 'pending',
+    "attempts" INTEGER NOT NULL DEFAULT 0,
+    "last_error" TEXT,
+    "worker_id" INTEGER,
+    "created_at" INTEGER NOT NULL,
+    "updated_at" INTEGER NOT NULL
+);
+CREATE INDEX "queue_status" ON "queue" ("status", "id");
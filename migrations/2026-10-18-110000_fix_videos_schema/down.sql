@@ -0,0 +1,22 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE "videos_old" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "uid" VARCHAR(11),
+    "link" VARCHAR(127),
+    "title" VARCHAR(255),
+    "author" VARCHAR(255),
+    "duration" INTEGER,
+    "description" VARCHAR(255),
+    "thumbnail_path" VARCHAR(255),
+    "date" INTEGER,
+    "other" BLOB
+);
+INSERT INTO "videos_old" SELECT * FROM "videos";
+-- Merged duplicates come back as they were, synthetic keys go back to no uid
+INSERT INTO "videos_old"
+SELECT "id", "uid", "link", "title", "author", "duration", "description", "thumbnail_path", "date", "other"
+FROM "videos_duplicates";
+UPDATE "videos_old" SET "uid" = NULL WHERE "uid" LIKE 'unkeyed %';
+DROP TABLE "videos_duplicates";
+DROP TABLE "videos";
+ALTER TABLE "videos_old" RENAME TO "videos";
@@ -0,0 +1,71 @@
+-- SQLite can't add constraints in place, so the table is rebuilt.
+-- Rows without a uid get it back from their link and bare 11-char IDs from older versions become "youtube <id>" keys.
+-- Rows nothing can be keyed on are kept as "unkeyed <id>", they still show up in `list` and can be fixed by hand
+CREATE TEMP TABLE "videos_keyed" AS
+SELECT *, ROW_NUMBER() OVER (PARTITION BY "key" ORDER BY "id") AS "dup"
+FROM (
+    SELECT *, CASE
+        WHEN "raw" IS NULL THEN 'unkeyed ' || "id"
+        WHEN length("raw") = 11 AND instr("raw", ' ') = 0 THEN 'youtube ' || "raw"
+        ELSE "raw"
+    END AS "key"
+    FROM (
+        SELECT *, COALESCE(NULLIF("uid", ''), CASE
+            WHEN instr("link", 'v=') > 0 THEN substr("link", instr("link", 'v=') + 2, 11)
+            WHEN instr("link", 'youtu.be/') > 0 THEN substr("link", instr("link", 'youtu.be/') + 9, 11)
+            WHEN instr("link", '/shorts/') > 0 THEN substr("link", instr("link", '/shorts/') + 8, 11)
+        END) AS "raw"
+        FROM "videos"
+    )
+);
+
+-- Duplicates are merged into the oldest row, which takes every column it's missing from the first of them that has
+-- it. They're also kept here as they were, since what they disagreed on is lost in the merge
+CREATE TABLE "videos_duplicates" (
+    "id" INTEGER PRIMARY KEY NOT NULL,
+    "merged_into" INTEGER NOT NULL,
+    "uid" VARCHAR(255),
+    "link" VARCHAR(127),
+    "title" VARCHAR(255),
+    "author" VARCHAR(255),
+    "duration" INTEGER,
+    "description" TEXT,
+    "thumbnail_path" VARCHAR(255),
+    "date" INTEGER,
+    "other" BLOB
+);
+INSERT INTO "videos_duplicates"
+SELECT d."id", k."id", d."uid", d."link", d."title", d."author", d."duration", d."description", d."thumbnail_path", d."date", d."other"
+FROM "videos_keyed" d
+JOIN "videos_keyed" k ON k."key" = d."key" AND k."dup" = 1
+WHERE d."dup" > 1;
+
+CREATE TABLE "videos_new" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "uid" VARCHAR(255) NOT NULL UNIQUE,
+    "link" VARCHAR(127),
+    "title" VARCHAR(255),
+    "author" VARCHAR(255),
+    "duration" INTEGER,
+    "description" TEXT,
+    "thumbnail_path" VARCHAR(255),
+    "date" INTEGER,
+    "other" BLOB
+);
+INSERT INTO "videos_new" ("id", "uid", "link", "title", "author", "duration", "description", "thumbnail_path", "date", "other")
+SELECT
+    k."id",
+    k."key",
+    (SELECT d."link" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."link" IS NOT NULL ORDER BY d."id" LIMIT 1),
+    (SELECT d."title" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."title" IS NOT NULL ORDER BY d."id" LIMIT 1),
+    (SELECT d."author" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."author" IS NOT NULL ORDER BY d."id" LIMIT 1),
+    (SELECT d."duration" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."duration" IS NOT NULL ORDER BY d."id" LIMIT 1),
+    (SELECT d."description" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."description" IS NOT NULL ORDER BY d."id" LIMIT 1),
+    (SELECT d."thumbnail_path" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."thumbnail_path" IS NOT NULL ORDER BY d."id" LIMIT 1),
+    (SELECT d."date" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."date" IS NOT NULL ORDER BY d."id" LIMIT 1),
+    (SELECT d."other" FROM "videos_keyed" d WHERE d."key" = k."key" AND d."other" IS NOT NULL ORDER BY d."id" LIMIT 1)
+FROM "videos_keyed" k
+WHERE k."dup" = 1;
+DROP TABLE "videos_keyed";
+DROP TABLE "videos";
+ALTER TABLE "videos_new" RENAME TO "videos";
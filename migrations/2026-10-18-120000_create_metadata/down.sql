@@ -0,0 +1,24 @@
+-- This file should undo anything in `up.sql`
+-- Columns with foreign keys can't be dropped, so the table is rebuilt
+CREATE TABLE "videos_old" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "uid" VARCHAR(255) NOT NULL UNIQUE,
+    "link" VARCHAR(127),
+    "title" VARCHAR(255),
+    "author" VARCHAR(255),
+    "duration" INTEGER,
+    "description" TEXT,
+    "thumbnail_path" VARCHAR(255),
+    "date" INTEGER,
+    "other" BLOB
+);
+INSERT INTO "videos_old"
+SELECT "id", "uid", "link", "title", "author", "duration", "description", "thumbnail_path", "date", "other" FROM "videos";
+DROP TABLE "videos";
+ALTER TABLE "videos_old" RENAME TO "videos";
+DROP TABLE IF EXISTS "chapters";
+DROP TABLE IF EXISTS "categories";
+DROP TABLE IF EXISTS "video_tags";
+DROP TABLE IF EXISTS "tags";
+DROP TABLE IF EXISTS "albums";
+DROP TABLE IF EXISTS "channels";
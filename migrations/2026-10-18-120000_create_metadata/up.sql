@@ -0,0 +1,46 @@
+-- Your SQL goes here
+CREATE TABLE "channels" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "uid" VARCHAR(255) NOT NULL UNIQUE,
+    "name" VARCHAR(255),
+    "url" VARCHAR(255),
+    "follower_count" INTEGER,
+    "verified" BOOLEAN NOT NULL DEFAULT 0
+);
+-- Artist is '' rather than NULL so the UNIQUE constraint also covers albums without one
+CREATE TABLE "albums" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "title" VARCHAR(255) NOT NULL,
+    "artist" VARCHAR(255) NOT NULL DEFAULT '',
+    "release_year" INTEGER,
+    UNIQUE ("title", "artist")
+);
+CREATE TABLE "tags" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "name" VARCHAR(255) NOT NULL UNIQUE
+);
+CREATE TABLE "video_tags" (
+    "video_id" INTEGER NOT NULL REFERENCES "videos" ("id") ON DELETE CASCADE,
+    "tag_id" INTEGER NOT NULL REFERENCES "tags" ("id") ON DELETE CASCADE,
+    PRIMARY KEY ("video_id", "tag_id")
+);
+CREATE TABLE "categories" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "video_id" INTEGER NOT NULL REFERENCES "videos" ("id") ON DELETE CASCADE,
+    "name" VARCHAR(255) NOT NULL,
+    UNIQUE ("video_id", "name")
+);
+CREATE TABLE "chapters" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "video_id" INTEGER NOT NULL REFERENCES "videos" ("id") ON DELETE CASCADE,
+    "position" INTEGER NOT NULL,
+    "title" VARCHAR(255),
+    "start_time" REAL NOT NULL,
+    "end_time" REAL NOT NULL
+);
+ALTER TABLE "videos" ADD COLUMN "channel_id" INTEGER REFERENCES "channels" ("id");
+ALTER TABLE "videos" ADD COLUMN "album_id" INTEGER REFERENCES "albums" ("id");
+ALTER TABLE "videos" ADD COLUMN "track" VARCHAR(255);
+CREATE INDEX "videos_channel" ON "videos" ("channel_id");
+CREATE INDEX "video_tags_tag" ON "video_tags" ("tag_id");
+CREATE INDEX "chapters_video" ON "chapters" ("video_id");
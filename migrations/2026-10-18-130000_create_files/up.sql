@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE "files" (
+    "id" INTEGER PRIMARY KEY ASC AUTOINCREMENT NOT NULL,
+    "video_id" INTEGER NOT NULL REFERENCES "videos" ("id") ON DELETE CASCADE,
+    "path" VARCHAR(1023) NOT NULL,
+    "role" VARCHAR(16) NOT NULL,
+    "size" INTEGER,
+    "container" VARCHAR(16),
+    "codec" VARCHAR(63),
+    "format_id" VARCHAR(63),
+    "checksum" VARCHAR(64),
+    "mtime" INTEGER,
+    UNIQUE ("video_id", "path")
+);
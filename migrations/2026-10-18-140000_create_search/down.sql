@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER "channels_fts_update";
+DROP TRIGGER "video_tags_fts_delete";
+DROP TRIGGER "video_tags_fts_insert";
+DROP TRIGGER "videos_fts_update";
+DROP TRIGGER "videos_fts_delete";
+DROP TRIGGER "videos_fts_insert";
+DROP VIEW "videos_fts_source";
+DROP TABLE "videos_fts";
@@ -0,0 +1,56 @@
+-- Your SQL goes here
+-- Full-text index over the library, rowid is videos.id. Author falls back to the channel name since yt-dlp only
+-- fills in an artist for music. Kept in sync by the triggers below so every writer gets it for free
+CREATE VIRTUAL TABLE "videos_fts" USING fts5(
+    "title",
+    "author",
+    "description",
+    "tags",
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE VIEW "videos_fts_source" AS
+SELECT
+    "videos"."id" AS "id",
+    "videos"."title" AS "title",
+    COALESCE("videos"."author", "channels"."name") AS "author",
+    "videos"."description" AS "description",
+    (
+        SELECT GROUP_CONCAT("tags"."name", ' ')
+        FROM "video_tags" JOIN "tags" ON "tags"."id" = "video_tags"."tag_id"
+        WHERE "video_tags"."video_id" = "videos"."id"
+    ) AS "tags"
+FROM "videos" LEFT JOIN "channels" ON "channels"."id" = "videos"."channel_id";
+
+INSERT INTO "videos_fts" ("rowid", "title", "author", "description", "tags")
+SELECT "id", "title", "author", "description", "tags" FROM "videos_fts_source";
+
+CREATE TRIGGER "videos_fts_insert" AFTER INSERT ON "videos" BEGIN
+    INSERT INTO "videos_fts" ("rowid", "title", "author", "description", "tags")
+    SELECT "id", "title", "author", "description", "tags" FROM "videos_fts_source" WHERE "id" = NEW."id";
+END;
+
+CREATE TRIGGER "videos_fts_delete" AFTER DELETE ON "videos" BEGIN
+    DELETE FROM "videos_fts" WHERE "rowid" = OLD."id";
+END;
+
+CREATE TRIGGER "videos_fts_update" AFTER UPDATE OF "title", "author", "description", "channel_id" ON "videos" BEGIN
+    DELETE FROM "videos_fts" WHERE "rowid" = OLD."id";
+    INSERT INTO "videos_fts" ("rowid", "title", "author", "description", "tags")
+    SELECT "id", "title", "author", "description", "tags" FROM "videos_fts_source" WHERE "id" = NEW."id";
+END;
+
+CREATE TRIGGER "video_tags_fts_insert" AFTER INSERT ON "video_tags" BEGIN
+    UPDATE "videos_fts" SET "tags" = (SELECT "tags" FROM "videos_fts_source" WHERE "id" = NEW."video_id")
+    WHERE "rowid" = NEW."video_id";
+END;
+
+CREATE TRIGGER "video_tags_fts_delete" AFTER DELETE ON "video_tags" BEGIN
+    UPDATE "videos_fts" SET "tags" = (SELECT "tags" FROM "videos_fts_source" WHERE "id" = OLD."video_id")
+    WHERE "rowid" = OLD."video_id";
+END;
+
+CREATE TRIGGER "channels_fts_update" AFTER UPDATE OF "name" ON "channels" BEGIN
+    UPDATE "videos_fts" SET "author" = NEW."name"
+    WHERE "rowid" IN (SELECT "id" FROM "videos" WHERE "channel_id" = NEW."id" AND "author" IS NULL);
+END;
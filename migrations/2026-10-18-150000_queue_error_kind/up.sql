@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- What kind of failure last_error describes, as reported by the worker
+ALTER TABLE "queue" ADD COLUMN "error_kind" VARCHAR(32);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- When a link that failed with a transient error may be handed out again, unix seconds
+ALTER TABLE "queue" ADD COLUMN "retry_at" BIGINT;
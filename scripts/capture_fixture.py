@@ -0,0 +1,99 @@
+"""Captures a finished progress hook payload from yt-dlp as a test fixture.
+
+    python3 scripts/capture_fixture.py <video URL> <name> [-f <format>] [--cookies <file>]
+    python3 scripts/capture_fixture.py <logs dir>/quarantine/<file>.json <name>
+
+Writes tests/fixtures/<name>.json, which `cargo test --test fixtures` picks up. Given a URL, the video is downloaded
+to a temporary directory (in the worst format unless told otherwise, only the metadata is kept, and only the first
+video of a playlist) and the last `finished` hook call is saved as yt-dlp made it. Given a dump the master
+quarantined, the payload in it is saved as the worker sent it, i.e. the bare info dict of a `VideoInfo`. Either way,
+signed media URLs and cookies are scrubbed, since captures come from real sessions. Nothing else is changed.
+"""
+import argparse
+import json
+import sys
+import tempfile
+from pathlib import Path
+from urllib.parse import urlsplit, urlunsplit
+
+FIXTURES = Path(__file__).resolve().parent.parent / "tests" / "fixtures"
+# Hosts whose query strings carry per-session signatures and the client's IP
+SIGNED_HOSTS = ("googlevideo.com", "youtube.com/api/timedtext")
+SECRET_HEADERS = ("Cookie", "Authorization", "X-Goog-Visitor-Id")
+
+
+def scrub_url(url: str) -> str:
+    if not any(h in url for h in SIGNED_HOSTS):
+        return url
+    parts = urlsplit(url)
+    return urlunsplit((parts.scheme, parts.netloc, parts.path, "", ""))
+
+
+def scrub(value):
+    if isinstance(value, dict):
+        for header in SECRET_HEADERS:
+            value.pop(header, None)
+        return {k: scrub(v) for k, v in value.items()}
+    if isinstance(value, list):
+        return [scrub(v) for v in value]
+    if isinstance(value, str) and value.startswith("http"):
+        return scrub_url(value)
+    return value
+
+
+def unwrap(dump: dict) -> dict:
+    if "payload" in dump and "kind" in dump:
+        if dump["kind"] != "VideoInfo":
+            sys.exit(f"Only VideoInfo payloads make fixtures, this is {dump['kind']}")
+        return json.loads(dump["payload"])
+    return dump
+
+
+def capture(url: str, format: str, cookies: str | None) -> dict:
+    import yt_dlp
+
+    finished = []
+
+    def hook(d):
+        # The info dict keeps changing after this, so it's serialized the way the worker would right away
+        if d["status"] == "finished":
+            finished.append(yt_dlp.YoutubeDL.sanitize_info(d))
+
+    with tempfile.TemporaryDirectory() as tmp:
+        params = {
+            "paths": {"home": tmp, "temp": tmp},
+            "format": format,
+            "playlist_items": "1",
+            "cookiefile": cookies,
+            "quiet": True,
+            "progress_hooks": [hook],
+        }
+        yt_dlp.YoutubeDL(params).download([url])
+    if not finished:
+        sys.exit(f"yt-dlp didn't finish downloading anything from {url}")
+    return finished[-1]
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("source", help="video URL or quarantined dump")
+    parser.add_argument("name", help="fixture name, without .json")
+    parser.add_argument("-f", "--format", default="worst", help="yt-dlp format to download when given a URL")
+    parser.add_argument("--cookies", help="Netscape cookies file for videos behind a login, e.g. age-restricted ones")
+    args = parser.parse_args()
+
+    target = FIXTURES / (args.name + ".json")
+    if target.exists():
+        sys.exit(f"{target} already exists")
+
+    if args.source.startswith(("http://", "https://")):
+        payload = capture(args.source, args.format, args.cookies)
+    else:
+        payload = unwrap(json.loads(Path(args.source).read_text()))
+    FIXTURES.mkdir(parents=True, exist_ok=True)
+    target.write_text(json.dumps(scrub(payload), indent=1, ensure_ascii=False) + "\n")
+    print(f"Wrote {target}, add a test for what makes it interesting to tests/fixtures.rs")
+
+
+if __name__ == "__main__":
+    main()
@@ -1,38 +1,315 @@
-#[path = "../comms.rs"]
-mod comms;
+use rhytm::{comms, links};
 use core::result::Result::Ok;
 
-use comms::{Message, MessageRead, MessageWrite};
-use log::Level;
+use comms::{
+    Capabilities, ErrorKind, Greeting, Message, MessageRead, MessageWrite, Outcome, PlaylistEntry, Progress, Status, HEARTBEAT_INTERVAL,
+    MASTER_ACCEPTS, MAX_FRAME_SIZE, WORKER_ACCEPTS,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
 use pyo3::{
+    exceptions::{PyIOError, PyKeyError},
     pyclass, pymethods,
-    types::{IntoPyDict, PyAnyMethods, PyModule, PyString, PyStringMethods},
-    Bound, IntoPy, Python,
+    types::{IntoPyDict, PyAnyMethods, PyDict, PyDictMethods, PyModule, PyString, PyStringMethods},
+    Bound, FromPyObject, IntoPy, PyAny, PyErr, PyResult, Python,
 };
 
+use std::collections::HashSet;
 use std::env;
 use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+/// The socket every writer goes through, so heartbeats don't end up in the middle of another frame
+type SharedSocket = Arc<Mutex<UnixStream>>;
+
+/// Post-processor hook that collects the files yt-dlp moved into place and reports them through `pp_fn`
+const PP_HOOK: &str = include_str!("pp_hook.py");
 
 #[pyclass]
 #[derive(Debug)]
 struct Callback {
     #[allow(dead_code)] // callback_function is called from Python
-    callback_function: fn(&Bound<PyString>, UnixStream),
-    ud: UnixStream,
+    callback_function: fn(&Bound<PyString>, &mut UnixStream),
+    ud: SharedSocket,
 }
 
 #[pymethods]
 impl Callback {
-    fn __call__(&self, d: &Bound<PyString>) -> () {
-        let _ = (self.callback_function)(
-            d,
-            self.ud
-                .try_clone()
-                .expect("Unable to clone datagram socket to callback function"),
-        );
+    fn __call__(&self, d: &Bound<PyString>) {
+        (self.callback_function)(d, &mut self.ud.lock().unwrap());
+    }
+}
+
+/// What the master asked of us outside of the batch exchange
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    /// Links to drop or interrupt, removed once they come up
+    cancelled: HashSet<String>,
+    /// `Some(graceful)` once the master told us to stop
+    shutdown: Option<bool>,
+    /// Link handed to yt-dlp right now
+    current_link: Option<String>,
+}
+
+impl ControlState {
+    /// Why the current download has to stop, if it has to
+    fn interruption(&self) -> Option<&'static str> {
+        if self.shutdown == Some(false) {
+            Some("Worker is shutting down")
+        } else if self
+            .current_link
+            .as_ref()
+            .is_some_and(|link| self.cancelled.contains(link))
+        {
+            Some("Cancelled by the master")
+        } else {
+            None
+        }
+    }
+}
+
+/// Control state shared between the socket reader thread and the download loop
+#[derive(Debug, Default)]
+struct Control {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+impl Control {
+    fn update(&self, f: impl FnOnce(&mut ControlState)) {
+        f(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+    }
+
+    /// Blocks while paused, unless there's a reason to stop
+    fn wait_unpaused(&self) -> MutexGuard<'_, ControlState> {
+        self.changed
+            .wait_while(self.state.lock().unwrap(), |s| {
+                s.paused && s.shutdown.is_none() && s.interruption().is_none()
+            })
+            .unwrap()
+    }
+
+    /// Waits out a pause before handing `link` to yt-dlp
+    fn start(&self, link: &str) -> Start {
+        let mut state = self.wait_unpaused();
+        if state.shutdown.is_some() {
+            Start::Stop
+        } else if state.cancelled.remove(link) {
+            Start::Cancelled
+        } else {
+            state.current_link = Some(link.to_owned());
+            Start::Go
+        }
+    }
+}
+
+enum Start {
+    Go,
+    Cancelled,
+    Stop,
+}
+
+/// Reads everything the master sends, applying control messages as they come and passing the rest on.
+/// Losing the master interrupts whatever is running, there's nobody left to report to
+fn read_messages(mut socket: UnixStream, max_frame_size: usize, control: Arc<Control>, replies: mpsc::Sender<Message>) {
+    loop {
+        match socket.read_json_msg::<Message>(max_frame_size) {
+            Ok(Message::Pause) => control.update(|s| s.paused = true),
+            Ok(Message::Resume) => control.update(|s| s.paused = false),
+            Ok(Message::Cancel { link }) => control.update(|s| {
+                s.cancelled.insert(link);
+            }),
+            // An urgent shutdown can't be downgraded
+            Ok(Message::Shutdown { graceful }) => control.update(|s| s.shutdown = Some(graceful && s.shutdown.unwrap_or(true))),
+            Ok(msg) => {
+                if replies.send(msg).is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                control.update(|s| s.shutdown = Some(false));
+                return;
+            }
+        }
+    }
+}
+
+/// Keeps the master from giving up on us during long extractions, merges or pauses
+fn send_heartbeats(socket: SharedSocket) {
+    loop {
+        thread::sleep(HEARTBEAT_INTERVAL);
+        if socket
+            .lock()
+            .unwrap()
+            .write_json_msg(&Message::Heartbeat)
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// yt-dlp progress hook. Sends the info dict once per video and a `Progress` for every tick, and is where pauses
+/// and interruptions take effect
+#[pyclass]
+struct ProgressHook {
+    socket: SharedSocket,
+    /// ID of the video whose info dict was sent last
+    current_video: Mutex<Option<String>>,
+    control: Arc<Control>,
+}
+
+fn get<'py, T: FromPyObject<'py>>(d: &Bound<'py, PyDict>, key: &str) -> PyResult<Option<T>> {
+    match d.get_item(key)? {
+        Some(v) => v.extract(),
+        None => Ok(None),
+    }
+}
+
+#[pymethods]
+impl ProgressHook {
+    fn __call__(&self, d: &Bound<PyDict>) -> PyResult<()> {
+        let py = d.py();
+        // yt-dlp lets `DownloadCancelled` through its own error handling, so raising it ends `download` for the link
+        let interruption = py.allow_threads(|| self.control.wait_unpaused().interruption());
+        if let Some(reason) = interruption {
+            let cancelled = py
+                .import_bound("yt_dlp.utils")?
+                .getattr("DownloadCancelled")?
+                .call1((reason,))?;
+            return Err(PyErr::from_value_bound(cancelled));
+        }
+
+        let info = d
+            .get_item("info_dict")?
+            .ok_or_else(|| PyKeyError::new_err("info_dict"))?
+            .downcast_into::<PyDict>()?;
+        let id: String = get(&info, "id")?.unwrap_or_default();
+        let mut socket = self.socket.lock().unwrap();
+
+        let mut current_video = self.current_video.lock().unwrap();
+        if current_video.as_deref() != Some(id.as_str()) {
+            let json: String = py
+                .import_bound("json")?
+                .call_method1("dumps", (&info,))?
+                .extract()?;
+            socket
+                .write_json_msg(&Message::VideoInfo(json))
+                .map_err(|e| PyIOError::new_err(e.to_string()))?;
+            *current_video = Some(id.clone());
+        }
+
+        let progress = Progress {
+            status: get::<String>(d, "status")?
+                .and_then(|s| Status::parse(&s))
+                .unwrap_or_default(),
+            id,
+            format_id: get(&info, "format_id")?.unwrap_or_default(),
+            filename: get(d, "filename")?.unwrap_or_default(),
+            downloaded_bytes: get(d, "downloaded_bytes")?.unwrap_or_default(),
+            total_bytes: get(d, "total_bytes")?,
+            total_bytes_estimate: get(d, "total_bytes_estimate")?,
+            speed: get(d, "speed")?,
+            eta: get(d, "eta")?,
+            elapsed: get(d, "elapsed")?,
+            fragment_index: get(d, "fragment_index")?,
+            fragment_count: get(d, "fragment_count")?,
+            real_download: get(&info, "__real_download")?.unwrap_or_default(),
+        };
+        socket
+            .write_json_msg(&Message::Progress(progress))
+            .map_err(|e| PyIOError::new_err(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Flat-extracts a playlist or channel link, `None` if yt-dlp didn't see a playlist there after all
+fn expand(expander: &Bound<PyAny>, link: &str) -> PyResult<Option<Vec<PlaylistEntry>>> {
+    let kwargs = vec![("download", false)].into_py_dict_bound(expander.py());
+    let info = expander.call_method("extract_info", (link,), Some(&kwargs))?;
+
+    let entries = info.call_method1("get", ("entries",))?;
+    if entries.is_none() {
+        return Ok(None);
+    }
+
+    let mut out = Vec::new();
+    for entry in entries.iter()? {
+        let entry = entry?;
+        // Unavailable videos show up as None
+        if entry.is_none() {
+            continue;
+        }
+        let get = |key: &str| -> PyResult<Option<String>> { entry.call_method1("get", (key,))?.extract() };
+        let Some(url) = get("url")?.or(get("webpage_url")?) else {
+            continue;
+        };
+        out.push(PlaylistEntry {
+            url,
+            title: get("title")?,
+            channel: get("channel")?.or(get("uploader")?),
+        });
+    }
+    Ok(Some(out))
+}
+
+/// Class names in `obj`'s MRO, most specific first
+fn class_names(obj: &Bound<PyAny>) -> Vec<String> {
+    let names = || -> PyResult<Vec<String>> {
+        obj.get_type()
+            .getattr("__mro__")?
+            .iter()?
+            .map(|class| class?.getattr("__name__")?.extract())
+            .collect()
+    };
+    names().unwrap_or_default()
+}
+
+/// Classifies an exception raised by `YoutubeDL.download`, looking through `DownloadError` at the exception it wraps
+fn describe_error(py: Python, e: &PyErr) -> (ErrorKind, String) {
+    let value = e.value_bound(py);
+    let cause = value
+        .getattr("exc_info")
+        .and_then(|info| info.get_item(1))
+        .ok()
+        .filter(|cause| !cause.is_none());
+
+    let mut names = cause.as_ref().map(class_names).unwrap_or_default();
+    names.extend(class_names(value));
+    let kind = ErrorKind::from_class_names(names.iter().map(String::as_str));
+
+    let message = value
+        .str()
+        .map(|m| m.to_string())
+        .unwrap_or_else(|_| e.to_string());
+    (kind, message.trim_start_matches("ERROR: ").to_owned())
+}
+
+/// What this worker brings to the table, sent along with the greeting
+fn capabilities(py: Python) -> Capabilities {
+    let yt_dlp_error = py.import_bound("yt_dlp").err().map(|e| e.to_string());
+    let yt_dlp_version = py
+        .import_bound("yt_dlp.version")
+        .and_then(|m| m.getattr("__version__")?.extract())
+        .ok();
+    let ffmpeg = py
+        .import_bound("yt_dlp.postprocessor.ffmpeg")
+        .and_then(|m| {
+            m.getattr("FFmpegPostProcessor")?
+                .call0()?
+                .getattr("available")?
+                .extract()
+        })
+        .unwrap_or(false);
+    Capabilities {
+        yt_dlp_version,
+        yt_dlp_error,
+        ffmpeg,
+        accepts: WORKER_ACCEPTS.map(str::to_owned).to_vec(),
     }
 }
 
@@ -47,33 +324,52 @@ fn main() -> Result<()> {
         .expect("THR_ID not set")
         .parse::<usize>()
         .unwrap();
-    let log_dir = env::var("LOG_DIR").expect("LOG_DIR not set");
+    let _log_dir = env::var("LOG_DIR").expect("LOG_DIR not set");
     let download_dir = env::var("DOWNLOAD_DIR").expect("DOWNLOAD_DIR not set");
     let tmp_dir = env::var("TMP_DIR").expect("TMP_DIR not set");
     let yt_dlp_output_template = env::var("YT_DLP_OUTPUT_TEMPLATE").expect("YT_DLP_OUTPUT_TEMPLATE not set");
+    let max_frame_size = env::var("MAX_FRAME_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(MAX_FRAME_SIZE);
 
-    let mut socket = UnixStream::connect(msp.clone()).expect(&format!("Unable to bind to socket @ {}", msp));
-
-    socket.write_json_msg(&Message::Greeting(thr_id)).unwrap();
+    let mut socket = UnixStream::connect(msp.clone()).unwrap_or_else(|e| panic!("Unable to bind to socket @ {}: {}", msp, e));
 
-    let msg = socket.read_json_msg::<Message>().unwrap();
+    pyo3::prepare_freethreaded_python();
+    let capabilities = Python::with_gil(capabilities);
+    socket.write_json_msg(&Message::Greeting(Greeting::new(thr_id, capabilities)))?;
 
-    if std::mem::discriminant(&msg) != std::mem::discriminant(&Message::Greeting(0)) {
-        print!("Received non-greeting message from server: {:?}", msg);
-        panic!("Server sent garbage");
+    let reply = socket
+        .read_json_msg(max_frame_size)
+        .context("Unable to read greeting, the master may be speaking an older protocol")
+        .and_then(comms::parse_greeting)?;
+    if reply.thr_id != thr_id {
+        bail!(
+            "Master greeted us with the wrong thread ID {}",
+            reply.thr_id
+        );
+    }
+    if let Some(reason) = reply.incompatibility(&MASTER_ACCEPTS) {
+        let _ = socket.write_json_msg(&Message::Rejected(reason.clone()));
+        bail!("Unable to work with this master: {}", reason);
     }
 
-    if let Message::Greeting(v) = msg {
-        if thr_id != v {
-            panic!("Server sent greeting with wrong id");
-        }
-    } else {
-        panic!("How the fuck are we getting here?");
+    // Control messages can arrive at any time, so a separate thread reads the socket from here on
+    let control = Arc::new(Control::default());
+    let (replies_tx, replies) = mpsc::channel();
+    {
+        let socket = socket.try_clone()?;
+        let control = Arc::clone(&control);
+        thread::spawn(move || read_messages(socket, max_frame_size, control, replies_tx));
+    }
+    let socket = Arc::new(Mutex::new(socket));
+    {
+        let socket = Arc::clone(&socket);
+        thread::spawn(move || send_heartbeats(socket));
     }
 
-    pyo3::prepare_freethreaded_python();
     //TODO: Move redundant init code here
-    Python::with_gil(|py| {
+    Python::with_gil(|py| -> Result<()> {
         //Override stdout to disable _all_ output from Python code
         let sys = py
             .import_bound("sys")
@@ -90,33 +386,27 @@ fn main() -> Result<()> {
                 .expect("Python: Unable to open /dev/null"),
         )
         .expect("Python: Unable to set stderr to /dev/null");
-        let callback = Callback {
-            callback_function: |d, mut ud| {
+        let progress_hook = ProgressHook {
+            socket: Arc::clone(&socket),
+            current_video: Mutex::new(None),
+            control: Arc::clone(&control),
+        };
+
+        let files_callback = Callback {
+            callback_function: |d, ud| {
                 let str = d.to_str().expect("Callback: Unable to parse json string");
 
-                ud.write_json_msg(&Message::JSON(str.to_string()))
-                    .expect("Callback: Unable to send JSON");
+                ud.write_json_msg(&Message::FilesMoved(str.to_string()))
+                    .expect("Callback: Unable to send FilesMoved");
             },
-            ud: socket
-                .try_clone()
-                .expect("Python: Unable to create a clone of socket to use in callback function"),
+            ud: Arc::clone(&socket),
         };
 
-        //Function for preprocessing JSON dictionary before sending it to Rust
-        //I know that this is fucked up but I am unable to figure out a better solution
-        let callback_preprocess = PyModule::from_code_bound(
-            py,
-            "\n\
-                import json\n\
-                def preproc_hook(dict):\n\
-                \tfn(json.dumps(dict))",
-            "",
-            "",
-        )
-        .unwrap();
+        //pp_hook reports where MoveFilesAfterDownload (always the last post-processor) put everything, missing targets default to the final dir
+        let callback_preprocess = PyModule::from_code_bound(py, PP_HOOK, "pp_hook.py", "pp_hook").unwrap();
 
         callback_preprocess
-            .setattr("fn", callback.into_py(py))
+            .setattr("pp_fn", files_callback.into_py(py))
             .unwrap();
 
         let params = vec![(
@@ -134,6 +424,7 @@ fn main() -> Result<()> {
         let out_tmpl = vec![("default", yt_dlp_output_template)].into_py_dict_bound(py);
 
         params.set_item("paths", paths).unwrap();
+        params.set_item("outtmpl", out_tmpl).unwrap();
         params.set_item("verbose", true).unwrap();
         params.set_item("quiet", false).unwrap();
         params.set_item("restrictfilenames", false).unwrap();
@@ -141,40 +432,140 @@ fn main() -> Result<()> {
         params.set_item("outtmpl_na_placeholder", "PLCHD").unwrap();
         params.set_item("http_chunk_size", 10485760).unwrap();
         params.set_item("fragment_retries", 5).unwrap();
+        params.set_item("writethumbnail", true).unwrap();
+        params
+            .set_item("progress_hooks", vec![progress_hook.into_py(py)])
+            .unwrap();
         params
             .set_item(
-                "progress_hooks",
-                vec![callback_preprocess.getattr("preproc_hook").unwrap()],
+                "postprocessor_hooks",
+                vec![callback_preprocess.getattr("pp_hook").unwrap()],
             )
             .unwrap();
         params.set_item("simulate", false).unwrap();
 
-        let args = vec![("params", params)].into_py_dict_bound(py);
+        let args = vec![("params", params.clone())].into_py_dict_bound(py);
 
-        let youtube_dl = py
-            .import_bound("yt_dlp")
-            .expect("Failed to import yt_dlp")
+        // The master turns us away at the greeting if this fails, so it only can against an older master
+        let yt_dlp = py.import_bound("yt_dlp").context("Unable to import yt_dlp")?;
+        let youtube_dl = yt_dlp
             .call_method("YoutubeDL", (), Some(&args))
             .expect("Python: Unable to create YoutubeDL object");
 
-        loop {
-            socket.write_json_msg(&Message::BatchRequest).unwrap();
-            match socket.read_json_msg::<Message>().unwrap() {
-                Message::Greeting(_) => {
-                    unimplemented!("Wrong batch header, Greeting instead of Batch possible server/client version mismatch")
-                }
+        // Separate instance that only lists playlist/channel entries without resolving them
+        let expander_params = vec![("extract_flat", "in_playlist")].into_py_dict_bound(py);
+        expander_params
+            .set_item(
+                "cookiesfrombrowser",
+                params.get_item("cookiesfrombrowser").unwrap(),
+            )
+            .unwrap();
+        expander_params.set_item("quiet", true).unwrap();
+        let expander = yt_dlp
+            .call_method(
+                "YoutubeDL",
+                (),
+                Some(&vec![("params", expander_params)].into_py_dict_bound(py)),
+            )
+            .expect("Python: Unable to create YoutubeDL object for playlist expansion");
+
+        'batches: loop {
+            if control.state.lock().unwrap().shutdown.is_some() {
+                break;
+            }
+            socket
+                .lock()
+                .unwrap()
+                .write_json_msg(&Message::BatchRequest)
+                .unwrap();
+            match replies.recv().context("Master hung up")? {
+                Message::Rejected(reason) => bail!("Rejected by master: {}", reason),
                 Message::Batch(batch) => {
                     for link in batch {
-                        socket.write_json_msg(&Message::DownloadStart).unwrap();
-                        let _ = youtube_dl.call_method1("download", (link.clone(),));
-                        socket.write_json_msg(&Message::DownloadEnd).unwrap();
+                        match py.allow_threads(|| control.start(&link)) {
+                            Start::Go => {}
+                            // Whatever is left of the batch is still in progress on the master's side, it takes
+                            // care of it once we disconnect
+                            Start::Stop => break 'batches,
+                            Start::Cancelled => {
+                                socket
+                                    .lock()
+                                    .unwrap()
+                                    .write_json_msg(&Message::DownloadResult {
+                                        link,
+                                        outcome: Outcome::Failed,
+                                        error_kind: Some(ErrorKind::Cancelled),
+                                        error_message: Some("Cancelled before it started".to_string()),
+                                        files: Vec::new(),
+                                    })
+                                    .unwrap();
+                                continue;
+                            }
+                        }
+
+                        if links::is_collection(&link) {
+                            match expand(&expander, &link) {
+                                Ok(Some(entries)) => {
+                                    socket
+                                        .lock()
+                                        .unwrap()
+                                        .write_json_msg(&Message::PlaylistEntries { link, entries })
+                                        .unwrap();
+                                    continue;
+                                }
+                                Ok(None) => {}
+                                // Failed like a download, so the master retries or gives up on it the same way
+                                Err(e) => {
+                                    let (kind, message) = describe_error(py, &e);
+                                    socket
+                                        .lock()
+                                        .unwrap()
+                                        .write_json_msg(&Message::DownloadResult {
+                                            link,
+                                            outcome: Outcome::Failed,
+                                            error_kind: Some(kind),
+                                            error_message: Some(format!("Unable to expand: {}", message)),
+                                            files: Vec::new(),
+                                        })
+                                        .unwrap();
+                                    continue;
+                                }
+                            }
+                        }
 
                         socket
-                            .write_json_msg(&Message::Log {
-                                thr_id,
-                                level: Level::Info,
-                                target: "Thread".to_string(),
-                                msg: format!("finished downloading video ID {}", link),
+                            .lock()
+                            .unwrap()
+                            .write_json_msg(&Message::DownloadStart { link: link.clone() })
+                            .unwrap();
+                        let result = youtube_dl.call_method1("download", (link.clone(),));
+                        control.update(|s| {
+                            s.cancelled.remove(&link);
+                            s.current_link = None;
+                        });
+
+                        let finished_files = callback_preprocess.getattr("finished_files").unwrap();
+                        let mut files: Vec<String> = finished_files.extract().unwrap_or_default();
+                        finished_files.call_method0("clear").unwrap();
+                        let mut seen = HashSet::new();
+                        files.retain(|f| seen.insert(f.clone()));
+
+                        let (outcome, error_kind, error_message) = match result {
+                            Ok(_) => (Outcome::Completed, None, None),
+                            Err(e) => {
+                                let (kind, message) = describe_error(py, &e);
+                                (Outcome::Failed, Some(kind), Some(message))
+                            }
+                        };
+                        socket
+                            .lock()
+                            .unwrap()
+                            .write_json_msg(&Message::DownloadResult {
+                                link,
+                                outcome,
+                                error_kind,
+                                error_message,
+                                files,
                             })
                             .unwrap();
                     }
@@ -182,16 +573,10 @@ fn main() -> Result<()> {
                 Message::EndRequest => {
                     break;
                 }
-                Message::Log { .. } => unimplemented!("Wrong batch header, Log instead of Batch, possible server/client version mismatch"),
-                Message::BatchRequest => unimplemented!("Wrong batch header, BatchRequest instead of Batch, possible server/client version mismatch"),
-                Message::JSON(_) => unimplemented!("Wrong batch header, JSON instead of Batch, possible server/client version mismatch"),
-                Message::DownloadStart => {
-                    unimplemented!("Wrong batch header, DownloadStart instead of Batch, possible server/client version mismatch")
-                }
-                Message::DownloadEnd => unimplemented!("Wrong batch header, DownloadEnd instead of Batch, possible server/client version mismatch"),
+                // Versions and accepted kinds were checked at the greeting, so this is a bug on either side
+                msg => bail!("Wrong batch header, {} instead of Batch", msg.kind()),
             }
         }
-    });
-
-    Ok(())
+        Ok(())
+    })
 }
@@ -0,0 +1,17 @@
+import json
+import os
+
+finished_files = []
+
+
+def pp_hook(d):
+    # Post-processors report under their pp_key, which is the class name without the trailing "PP"
+    if d['status'] != 'finished' or d['postprocessor'] != 'MoveFilesAfterDownload':
+        return
+    info = d['info_dict']
+    finaldir = os.path.dirname(info['filepath'])
+    moved = {old: new or os.path.join(finaldir, os.path.basename(old)) for old, new in (info.get('__files_to_move') or {}).items()}
+    finished_files.append(info['filepath'])
+    finished_files.extend(moved.values())
+    pp_fn(json.dumps({'id': info.get('id'), 'extractor_key': info.get('extractor_key'), 'filepath': info['filepath'],
+        'format_id': info.get('format_id'), 'ext': info.get('ext'), 'vcodec': info.get('vcodec'), 'acodec': info.get('acodec'), 'moved': moved}))
@@ -1,61 +1,281 @@
-use std::{io::Read, io::Write, os::unix::net::UnixStream};
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::Read,
+    io::Write,
+    os::unix::net::UnixStream,
+    time::Duration,
+};
 
-use anyhow::{Context, Error, Ok};
-use clap::Parser;
+use anyhow::{Context, Error};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const THREAD_COUNT: usize = 1;
 const LINK_BATCH_SIZE: usize = 5;
 const TMP_DIR: &str = "/tmp/rhytm"; // TODO: parse from args
 const DOWNLOAD_DIR: &str = "."; // TODO: parse from args
 const LOGS_DIR_RELATIVE: &str = "/logs/";
-const PARSE_REGEX_STR: &str = r"(https://(music)|(www)\.youtube\.com/)?(watch\?v=)([a-zA-Z0-9/\.\?=\-_]+)";
+const PARSE_REGEX_STR: &str = r"(https://(music)|(www)\.youtube\.com/)?(watch\?v=)(?P<id>[a-zA-Z0-9/\.\?=\-_]+)";
 const YT_DLP_OUTPUT_TEMPLATE: &str = "%(title,fulltitle)s - %(uploader)s - [%(id)s]";
+const INACTIVITY_TIMEOUT: u64 = 600;
+const MAX_RESPAWNS: usize = 3;
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: u64 = 30;
+const RETRY_JITTER: f64 = 0.25;
 
+/// Bump whenever a message changes shape, master and workers refuse to talk across versions
+pub const PROTOCOL_VERSION: u32 = 7;
+/// Frames are prefixed with their length as a little-endian u32
+pub type FrameLength = u32;
+/// Largest frame we accept by default, info dicts with every format listed run into a few MiB
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+/// How often workers let the master know they're alive
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Silence after which the master considers a worker dead, also how long a spawned worker gets to connect
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(6 * HEARTBEAT_INTERVAL.as_secs());
+/// Longest extractor key, ID or format ID that goes into a download log's name, keeping it well under NAME_MAX
+pub const LOG_NAME_PART_MAX: usize = 64;
+
+/// Messages the master accepts from workers
+pub const MASTER_ACCEPTS: [&str; 11] = [
+    "Greeting",
+    "Heartbeat",
+    "Log",
+    "BatchRequest",
+    "VideoInfo",
+    "Progress",
+    "PlaylistEntries",
+    "FilesMoved",
+    "DownloadStart",
+    "DownloadResult",
+    "Rejected",
+];
+/// Messages workers accept from the master
+pub const WORKER_ACCEPTS: [&str; 8] = [
+    "Greeting",
+    "Batch",
+    "EndRequest",
+    "Pause",
+    "Resume",
+    "Cancel",
+    "Shutdown",
+    "Rejected",
+];
+
+/// Why a frame couldn't be read or written. Anything but `Malformed` leaves the stream out of sync, so the peer has
+/// to be dropped either way
+#[derive(Debug)]
+pub enum FrameError {
+    /// Length prefix above the configured maximum, the body is left unread
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The peer hung up between frames
+    Closed,
+    /// The peer hung up in the middle of a frame
+    Truncated,
+    /// The body isn't the JSON we expected
+    Malformed(serde_json::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::FrameTooLarge { size, max } => write!(f, "frame of {} bytes exceeds the maximum of {}", size, max),
+            FrameError::Closed => write!(f, "connection closed"),
+            FrameError::Truncated => write!(f, "connection closed in the middle of a frame"),
+            FrameError::Malformed(e) => write!(f, "malformed frame: {}", e),
+            FrameError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Malformed(e) => Some(e),
+            FrameError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// EOF while reading a frame means the peer went away, `Closed` if nothing of the frame was read yet
+fn frame_io_error(e: std::io::Error, started: bool) -> FrameError {
+    match (e.kind(), started) {
+        (std::io::ErrorKind::UnexpectedEof, false) => FrameError::Closed,
+        (std::io::ErrorKind::UnexpectedEof, true) => FrameError::Truncated,
+        _ => FrameError::Io(e),
+    }
+}
+
+fn frame_size(lbuf: [u8; std::mem::size_of::<FrameLength>()], max_size: usize) -> Result<usize, FrameError> {
+    let size = FrameLength::from_le_bytes(lbuf) as usize;
+    if size > max_size {
+        return Err(FrameError::FrameTooLarge {
+            size,
+            max: max_size,
+        });
+    }
+    Ok(size)
+}
+
+/// Length prefix followed by the JSON body
+fn encode_frame<T: Serialize>(msg: &T) -> Result<Vec<u8>, FrameError> {
+    let body = serde_json::to_vec(msg).map_err(FrameError::Malformed)?;
+    let size = FrameLength::try_from(body.len()).map_err(|_| FrameError::FrameTooLarge {
+        size: body.len(),
+        max: FrameLength::MAX as usize,
+    })?;
+
+    let mut frame = Vec::with_capacity(std::mem::size_of::<FrameLength>() + body.len());
+    frame.extend_from_slice(&size.to_le_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+#[allow(dead_code)] // the master only uses the async codec
 pub trait MessageRead: std::io::Read {
-    fn read_json_msg<T: for<'a> Deserialize<'a>>(&mut self) -> Result<T, Error>;
+    /// Reads one frame, refusing to allocate more than `max_size` bytes for it
+    fn read_json_msg<T: for<'a> Deserialize<'a>>(&mut self, max_size: usize) -> Result<T, FrameError>;
 }
 
 impl MessageRead for UnixStream {
-    fn read_json_msg<T: for<'a> Deserialize<'a>>(&mut self) -> Result<T, Error> {
-        let mut lbuf = [0 as u8; std::mem::size_of::<usize>()];
-        self.read_exact(&mut lbuf)
-            .context("Unable to read msg length")?;
-
-        let size = usize::from_ne_bytes(lbuf.try_into().unwrap());
+    fn read_json_msg<T: for<'a> Deserialize<'a>>(&mut self, max_size: usize) -> Result<T, FrameError> {
+        let mut lbuf = [0u8; std::mem::size_of::<FrameLength>()];
+        // Read the first byte on its own so a peer hanging up between frames isn't mistaken for a truncated frame
+        self.read_exact(&mut lbuf[..1])
+            .map_err(|e| frame_io_error(e, false))?;
+        self.read_exact(&mut lbuf[1..])
+            .map_err(|e| frame_io_error(e, true))?;
+        let size = frame_size(lbuf, max_size)?;
 
-        let mut buf = Vec::with_capacity(size);
-        buf.resize(size, 0);
+        let mut buf = vec![0; size];
         self.read_exact(&mut buf)
-            .context("Unable to read message")?;
-        serde_json::from_slice(&buf).context("Unable to deserialize")
+            .map_err(|e| frame_io_error(e, true))?;
+        serde_json::from_slice(&buf).map_err(FrameError::Malformed)
     }
 }
 
+#[allow(dead_code)]
 pub trait MessageWrite: std::io::Write {
-    fn write_json_msg<T: Serialize>(&mut self, msg: &T) -> Result<usize, Error>;
+    fn write_json_msg<T: Serialize>(&mut self, msg: &T) -> Result<usize, FrameError>;
 }
 
 impl MessageWrite for UnixStream {
-    fn write_json_msg<T: Serialize>(&mut self, msg: &T) -> Result<usize, Error> {
-        let msg = serde_json::to_vec(msg).context("Unable to serialize")?;
-        let size = msg.len();
+    fn write_json_msg<T: Serialize>(&mut self, msg: &T) -> Result<usize, FrameError> {
+        let frame = encode_frame(msg)?;
+        self.write_all(&frame).map_err(FrameError::Io)?;
+        Ok(frame.len() - std::mem::size_of::<FrameLength>())
+    }
+}
 
-        self.write_all(&size.to_ne_bytes())
-            .context("Unable to write msg length")?;
-        self.write_all(&msg).context("Unable to write message")?;
-        Ok(size)
+/// `MessageRead` for tokio streams, same framing
+#[allow(async_fn_in_trait)] // only called on concrete streams, so the futures stay Send where the stream is
+pub trait AsyncMessageRead: AsyncRead + Unpin {
+    async fn read_json_msg<T: for<'a> Deserialize<'a>>(&mut self, max_size: usize) -> Result<T, FrameError>;
+}
+
+impl<S: AsyncRead + Unpin> AsyncMessageRead for S {
+    async fn read_json_msg<T: for<'a> Deserialize<'a>>(&mut self, max_size: usize) -> Result<T, FrameError> {
+        let mut lbuf = [0u8; std::mem::size_of::<FrameLength>()];
+        self.read_exact(&mut lbuf[..1])
+            .await
+            .map_err(|e| frame_io_error(e, false))?;
+        self.read_exact(&mut lbuf[1..])
+            .await
+            .map_err(|e| frame_io_error(e, true))?;
+        let size = frame_size(lbuf, max_size)?;
+
+        let mut buf = vec![0; size];
+        self.read_exact(&mut buf)
+            .await
+            .map_err(|e| frame_io_error(e, true))?;
+        serde_json::from_slice(&buf).map_err(FrameError::Malformed)
+    }
+}
+
+/// `MessageWrite` for tokio streams, same framing
+#[allow(async_fn_in_trait)]
+pub trait AsyncMessageWrite: AsyncWrite + Unpin {
+    async fn write_json_msg<T: Serialize>(&mut self, msg: &T) -> Result<usize, FrameError>;
+}
+
+impl<S: AsyncWrite + Unpin> AsyncMessageWrite for S {
+    async fn write_json_msg<T: Serialize>(&mut self, msg: &T) -> Result<usize, FrameError> {
+        let frame = encode_frame(msg)?;
+        self.write_all(&frame).await.map_err(FrameError::Io)?;
+        Ok(frame.len() - std::mem::size_of::<FrameLength>())
+    }
+}
+
+/// Checks the peer's first frame, which is read as plain JSON so that a peer from another revision gets a clear
+/// error instead of a deserialization failure
+pub fn parse_greeting(msg: Value) -> Result<Greeting, Error> {
+    if let Some(reason) = msg.get("Rejected").and_then(Value::as_str) {
+        anyhow::bail!("Rejected by peer: {}", reason);
+    }
+    let greeting = msg
+        .get("Greeting")
+        .with_context(|| format!("Expected a greeting, got {}", msg))?;
+    let version = greeting.get("protocol_version").and_then(Value::as_u64);
+    if version != Some(PROTOCOL_VERSION.into()) {
+        anyhow::bail!(
+            "Peer speaks protocol version {}, we speak {}",
+            version.map_or("unknown".to_owned(), |v| v.to_string()),
+            PROTOCOL_VERSION
+        );
     }
+    serde_json::from_value(greeting.clone()).context("Unable to parse greeting")
 }
 
 #[derive(Parser, Debug, Serialize, Deserialize)]
 #[command(version, author, about, long_about = None)]
 pub struct Options {
-    #[arg(short, long, default_value = "info")]
+    #[arg(short, long, global = true, default_value = "info")]
     pub verbosity: LevelFilter,
 
+    #[arg(short, long, global = true, default_value = DOWNLOAD_DIR)]
+    pub download_dir: String,
+
+    #[arg(short, long, global = true, default_value = LOGS_DIR_RELATIVE)]
+    pub logs_dir_relative: String,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
+pub enum Command {
+    /// Download everything linked from a page, list or playlist export
+    Download(DownloadOptions),
+    /// List videos in the library
+    List(QueryOptions),
+    /// Full-text search over titles, authors, descriptions and tags
+    Search {
+        /// FTS5 query, e.g. `"live session" OR acoustic`, `title:remix`, `rock NOT cover`
+        query: String,
+
+        #[command(flatten)]
+        query_options: QueryOptions,
+    },
+    /// Parse the worker payloads set aside in the logs dir's quarantine again and record the ones that work now
+    ReplayQuarantine,
+    /// Rebuild links.db from the download logs in the logs dir, adding what's missing and reporting conflicts
+    RebuildDb {
+        /// Move the existing links.db aside and start from an empty one, e.g. when it's corrupt
+        #[arg(long)]
+        fresh: bool,
+    },
+}
+
+#[derive(Args, Debug, Serialize, Deserialize)]
+pub struct DownloadOptions {
     #[arg(short='j', long, default_value_t = THREAD_COUNT)]
     pub threads: usize,
 
@@ -65,25 +285,193 @@ pub struct Options {
     #[arg(short, long, default_value = TMP_DIR)]
     pub tmp_dir: String,
 
-    #[arg(short, long, default_value = DOWNLOAD_DIR)]
-    pub download_dir: String,
-
-    #[arg(short, long, default_value = LOGS_DIR_RELATIVE)]
-    pub logs_dir_relative: String,
-
     #[arg(short, long, default_value = PARSE_REGEX_STR)]
     pub parse_regex_str: String,
 
     #[arg(short, long, default_value = YT_DLP_OUTPUT_TEMPLATE)]
     pub yt_dlp_output_template: String,
 
+    #[arg(short, long, value_enum, default_value_t = InputFormat::Auto)]
+    pub input_format: InputFormat,
+
+    /// Largest message in bytes accepted from workers and, in turn, from the master
+    #[arg(long, default_value_t = MAX_FRAME_SIZE)]
+    pub max_frame_size: usize,
+
+    /// Seconds a download may go without progress before it is cancelled, its worker is replaced if it stays stuck as long again
+    #[arg(long, default_value_t = INACTIVITY_TIMEOUT)]
+    pub inactivity_timeout: u64,
+
+    /// How many times a worker that died or hung is replaced
+    #[arg(long, default_value_t = MAX_RESPAWNS)]
+    pub max_respawns: usize,
+
+    /// Tries per link and run before giving up on it until the next run, the first one included
+    #[arg(long, default_value_t = MAX_ATTEMPTS)]
+    pub max_attempts: u32,
+
+    /// Seconds before retrying a link that failed for a transient reason, doubled for every retry after the first
+    #[arg(long, default_value_t = RETRY_BACKOFF)]
+    pub retry_backoff: u64,
+
+    /// Random spread of retry delays, as a fraction of the delay
+    #[arg(long, default_value_t = RETRY_JITTER)]
+    pub retry_jitter: f64,
+
+    /// Queue links that failed permanently (private, removed, members-only...) again
+    #[arg(long)]
+    pub recheck: bool,
+
+    /// Treat keys yt-dlp sends that we don't know about as a parse failure instead of keeping them, to catch
+    /// schema drift while developing
+    #[arg(long)]
+    pub strict_schema: bool,
+
+    /// Where to write the JSON run report, `run-<timestamp>.json` in the logs dir by default
+    #[arg(long)]
+    pub report: Option<String>,
+
+    /// Saved page, link list or playlist export, `-` to read from stdin
     #[arg(required(true))]
-    pub html_path: String,
+    pub input_path: String,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize)]
+pub struct QueryOptions {
+    /// Channel name (substring, case-insensitive) or ID
+    #[arg(short, long)]
+    pub channel: Option<String>,
+
+    /// Shortest duration, in seconds or `[h:]m:s`
+    #[arg(long, value_parser = parse_duration)]
+    pub min_duration: Option<i64>,
+
+    /// Longest duration, in seconds or `[h:]m:s`
+    #[arg(long, value_parser = parse_duration)]
+    pub max_duration: Option<i64>,
+
+    /// Uploaded on or after, `YYYY-MM-DD` or `YYYYMMDD`
+    #[arg(long, value_parser = parse_date)]
+    pub after: Option<i64>,
+
+    /// Uploaded on or before, `YYYY-MM-DD` or `YYYYMMDD`
+    #[arg(long, value_parser = parse_date)]
+    pub before: Option<i64>,
+
+    /// Defaults to relevance for `search` and to date for `list`
+    #[arg(short, long, value_enum)]
+    pub sort: Option<SortKey>,
+
+    #[arg(short, long)]
+    pub reverse: bool,
+
+    #[arg(short = 'n', long)]
+    pub limit: Option<usize>,
+
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortKey {
+    /// Best match first, only meaningful for `search`
+    Relevance,
+    /// Newest upload first
+    Date,
+    Title,
+    Channel,
+    /// Longest first
+    Duration,
+    /// Most recently downloaded first
+    Added,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// Seconds, `m:s` or `h:m:s`. Minutes and seconds that come after a bigger unit go up to 59
+fn parse_duration(s: &str) -> Result<i64, String> {
+    let error = |why: &str| format!("{:?} is not a duration, expected seconds or [h:]m:s: {}", s, why);
+    let parts: Vec<&str> = s.split(':').map(str::trim).collect();
+    if parts.len() > 3 {
+        return Err(error("too many parts"));
+    }
+    let mut seconds = 0i64;
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(error("every part has to be a whole number, zero or more"));
+        }
+        let n: i64 = part.parse().map_err(|_| error("too long"))?;
+        if i > 0 && n >= 60 {
+            return Err(error("minutes and seconds go up to 59"));
+        }
+        seconds = seconds
+            .checked_mul(60)
+            .and_then(|s| s.checked_add(n))
+            .ok_or_else(|| error("too long"))?;
+    }
+    Ok(seconds)
+}
+
+/// `YYYY-MM-DD` or `YYYYMMDD`, as the `YYYYMMDD` number `videos.date` holds
+fn parse_date(s: &str) -> Result<i64, String> {
+    let error = |why: &str| format!("{:?} is not a date, expected YYYY-MM-DD or YYYYMMDD: {}", s, why);
+    let digits = match s.split('-').collect::<Vec<_>>()[..] {
+        [ymd] if ymd.len() == 8 => ymd.to_owned(),
+        [y, m, d] if y.len() == 4 && m.len() == 2 && d.len() == 2 => format!("{}{}{}", y, m, d),
+        _ => return Err(error("wrong length")),
+    };
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(error("not a number"));
+    }
+    let number = |range: std::ops::Range<usize>| digits[range].parse::<u32>().map_err(|_| error("not a number"));
+    let (year, month, day) = (number(0..4)?, number(4..6)?, number(6..8)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Err(error("no such month")),
+    };
+    if day == 0 || day > days {
+        return Err(error("no such day in that month"));
+    }
+    Ok(i64::from(year) * 10000 + i64::from(month) * 100 + i64::from(day))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputFormat {
+    /// Guess from the extension, then from the content
+    Auto,
+    /// Saved page, scanned with the parse regex
+    Html,
+    /// Saved YouTube/YouTube Music playlist page, parsed as a DOM
+    Page,
+    /// Newline-separated URLs or IDs
+    List,
+    /// M3U/M3U8 playlist
+    M3u,
+    /// XSPF playlist
+    Xspf,
+    /// Google Takeout watch-history.json
+    TakeoutJson,
+    /// Google Takeout playlist CSV
+    TakeoutCsv,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Message {
-    Greeting(usize),
+    /// First message in both directions
+    Greeting(Greeting),
+    /// Sent instead of a greeting when the peer can't be talked to, the connection is closed right after
+    Rejected(String),
+    /// Sent by workers every `HEARTBEAT_INTERVAL`, whatever they're doing
+    Heartbeat,
     Log {
         thr_id: usize,
         level: log::Level,
@@ -92,24 +480,401 @@ pub enum Message {
     },
     BatchRequest,
     Batch(Vec<String>),
-    JSON(String),
-    DownloadStart,
-    DownloadEnd,
+    /// JSON info dict, sent before the first `Progress` of every video
+    VideoInfo(String),
+    Progress(Progress),
+    /// Videos found by flat-extracting a playlist or channel link
+    PlaylistEntries {
+        link: String,
+        entries: Vec<PlaylistEntry>,
+    },
+    /// JSON `MovedFiles`, sent once yt-dlp has moved everything to its final location
+    FilesMoved(String),
+    /// Sent right before `link` is handed to yt-dlp, the link a `Cancel` for the current download names
+    DownloadStart {
+        link: String,
+    },
+    /// Sent after every link handed to yt-dlp, whether it raised or not
+    DownloadResult {
+        link: String,
+        outcome: Outcome,
+        error_kind: Option<ErrorKind>,
+        error_message: Option<String>,
+        /// Final paths of everything yt-dlp left on disk for the link
+        files: Vec<String>,
+    },
     EndRequest,
+    /// Holds the worker at its next progress tick or link until `Resume`
+    Pause,
+    Resume,
+    /// Interrupts `link` if it is being downloaded, or drops it if it hasn't started yet
+    Cancel {
+        link: String,
+    },
+    /// Stops the worker once its current download finishes, or right away (interrupting it) if not `graceful`
+    Shutdown {
+        graceful: bool,
+    },
+}
+
+impl Message {
+    /// Variant name, as listed in `Capabilities::accepts`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Greeting(_) => "Greeting",
+            Message::Rejected(_) => "Rejected",
+            Message::Heartbeat => "Heartbeat",
+            Message::Log { .. } => "Log",
+            Message::BatchRequest => "BatchRequest",
+            Message::Batch(_) => "Batch",
+            Message::VideoInfo(_) => "VideoInfo",
+            Message::Progress(_) => "Progress",
+            Message::PlaylistEntries { .. } => "PlaylistEntries",
+            Message::FilesMoved(_) => "FilesMoved",
+            Message::DownloadStart { .. } => "DownloadStart",
+            Message::DownloadResult { .. } => "DownloadResult",
+            Message::EndRequest => "EndRequest",
+            Message::Pause => "Pause",
+            Message::Resume => "Resume",
+            Message::Cancel { .. } => "Cancel",
+            Message::Shutdown { .. } => "Shutdown",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Greeting {
+    pub protocol_version: u32,
+    pub thr_id: usize,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Capabilities {
+    /// `yt_dlp.version.__version__`, workers only
+    pub yt_dlp_version: Option<String>,
+    /// Why yt-dlp couldn't be imported, workers only. Such a worker can't download anything and is turned away
+    pub yt_dlp_error: Option<String>,
+    /// Whether yt-dlp found ffmpeg, without it separate streams can't be merged. Workers only
+    pub ffmpeg: bool,
+    /// Message kinds the sender is able to handle
+    pub accepts: Vec<String>,
+}
+
+impl Greeting {
+    pub fn new(thr_id: usize, capabilities: Capabilities) -> Self {
+        Greeting {
+            protocol_version: PROTOCOL_VERSION,
+            thr_id,
+            capabilities,
+        }
+    }
+
+    /// Why we can't talk to the peer that sent this greeting, if we'll be sending it any of `sends`
+    pub fn incompatibility(&self, sends: &[&str]) -> Option<String> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Some(format!(
+                "protocol version {} instead of {}",
+                self.protocol_version, PROTOCOL_VERSION
+            ));
+        }
+        let missing: Vec<&str> = sends
+            .iter()
+            .copied()
+            .filter(|kind| !self.capabilities.accepts.iter().any(|a| a == kind))
+            .collect();
+        (!missing.is_empty()).then(|| format!("peer does not accept {}", missing.join(", ")))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// yt-dlp returned normally, which includes finding everything already downloaded
+    Completed,
+    /// yt-dlp raised
+    Failed,
+}
+
+/// What went wrong, from the class of the exception yt-dlp raised (or the one wrapped by its `DownloadError`)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// `GeoRestrictedError`
+    GeoRestricted,
+    /// `UnsupportedError`, no extractor for the URL
+    Unsupported,
+    /// Any other `ExtractorError`: private, removed, members-only, age-gated...
+    Extractor,
+    /// HTTP and transport errors, timeouts, incomplete reads
+    Network,
+    /// `PostProcessingError`, e.g. a failed merge
+    Postprocessing,
+    /// `DownloadError` without a more specific cause
+    Download,
+    /// `DownloadCancelled`, raised by our own hook when the master cancels the link or shuts the worker down
+    Cancelled,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::GeoRestricted => "geo_restricted",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::Extractor => "extractor",
+            ErrorKind::Network => "network",
+            ErrorKind::Postprocessing => "postprocessing",
+            ErrorKind::Download => "download",
+            ErrorKind::Cancelled => "cancelled",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// Picks the most specific kind out of the class names in an exception's MRO
+    #[allow(dead_code)] // only the worker sees exceptions
+    pub fn from_class_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        for name in names {
+            let kind = match name {
+                "GeoRestrictedError" => ErrorKind::GeoRestricted,
+                "UnsupportedError" => ErrorKind::Unsupported,
+                "ExtractorError" => ErrorKind::Extractor,
+                "PostProcessingError" => ErrorKind::Postprocessing,
+                "TransportError" | "HTTPError" | "URLError" | "IncompleteRead" | "ContentTooShortError" | "TimeoutError" | "ConnectionError" => {
+                    ErrorKind::Network
+                }
+                "DownloadCancelled" => ErrorKind::Cancelled,
+                "DownloadError" => ErrorKind::Download,
+                _ => continue,
+            };
+            return kind;
+        }
+        ErrorKind::Other
+    }
+}
+
+/// One progress hook call, stripped down to what changes between ticks
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    pub status: Status,
+    /// Video the tick belongs to, matches the last `VideoInfo`
+    pub id: String,
+    /// Stream being downloaded, one of the info dict's formats
+    pub format_id: String,
+    pub filename: String,
+    pub downloaded_bytes: u64,
+    pub total_bytes: Option<u64>,
+    pub total_bytes_estimate: Option<f64>,
+    /// Bytes per second
+    pub speed: Option<f64>,
+    /// Seconds
+    pub eta: Option<f64>,
+    pub elapsed: Option<f64>,
+    pub fragment_index: Option<u64>,
+    pub fragment_count: Option<u64>,
+    /// yt-dlp's `__real_download`, false if the file was already there
+    pub real_download: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PlaylistEntry {
+    pub url: String,
+    pub title: Option<String>,
+    pub channel: Option<String>,
+}
+
+/// Where the files of a finished download ended up, built by the worker's post-processor hook
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MovedFiles {
+    pub id: String,
+    pub extractor_key: String,
+    /// Final media file, merged if the video and audio were downloaded separately
+    pub filepath: String,
+    pub format_id: Option<String>,
+    pub ext: Option<String>,
+    pub vcodec: Option<String>,
+    pub acodec: Option<String>,
+    /// Temporary path -> final path, for the media file and everything written next to it
+    pub moved: HashMap<String, String>,
+}
+
+/// Where a download stands, as yt-dlp's progress hook reports it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Downloading,
+    Finished,
+    Error,
+}
+
+impl Status {
+    /// Reads a progress hook's `status`, `None` for anything yt-dlp isn't known to send
+    #[allow(dead_code)] // only the worker reads hook dicts
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "downloading" => Some(Status::Downloading),
+            "finished" => Some(Status::Finished),
+            "error" => Some(Status::Error),
+            _ => None,
+        }
+    }
+}
+
+/// yt-dlp's `has_drm`, a boolean unless it can't tell, in which case it's the string `"maybe"`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(try_from = "Value", into = "Value")]
+pub enum DrmState {
+    Yes,
+    #[default]
+    No,
+    Maybe,
+}
+
+impl TryFrom<Value> for DrmState {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(true) => Ok(DrmState::Yes),
+            Value::Bool(false) => Ok(DrmState::No),
+            Value::String(s) if s == "maybe" => Ok(DrmState::Maybe),
+            other => Err(format!("expected a boolean or \"maybe\", got {}", other)),
+        }
+    }
+}
+
+impl From<DrmState> for Value {
+    fn from(drm: DrmState) -> Self {
+        match drm {
+            DrmState::Yes => Value::Bool(true),
+            DrmState::No => Value::Bool(false),
+            DrmState::Maybe => Value::String("maybe".to_owned()),
+        }
+    }
+}
+
+/// yt-dlp's `live_status`. Values added after this was written are kept as `Other` rather than failing the parse
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum LiveStatus {
+    #[default]
+    NotLive,
+    IsLive,
+    IsUpcoming,
+    WasLive,
+    /// Was live, but the VOD isn't processed yet
+    PostLive,
+    Other(String),
+}
+
+impl LiveStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            LiveStatus::NotLive => "not_live",
+            LiveStatus::IsLive => "is_live",
+            LiveStatus::IsUpcoming => "is_upcoming",
+            LiveStatus::WasLive => "was_live",
+            LiveStatus::PostLive => "post_live",
+            LiveStatus::Other(s) => s,
+        }
+    }
+}
+
+impl From<String> for LiveStatus {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "not_live" => LiveStatus::NotLive,
+            "is_live" => LiveStatus::IsLive,
+            "is_upcoming" => LiveStatus::IsUpcoming,
+            "was_live" => LiveStatus::WasLive,
+            "post_live" => LiveStatus::PostLive,
+            _ => LiveStatus::Other(s),
+        }
+    }
+}
+
+impl From<LiveStatus> for String {
+    fn from(status: LiveStatus) -> Self {
+        status.as_str().to_owned()
+    }
+}
+
+/// yt-dlp's `availability`. Values added after this was written are kept as `Other` rather than failing the parse
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum Availability {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+    PremiumOnly,
+    SubscriberOnly,
+    NeedsAuth,
+    Other(String),
+}
+
+impl Availability {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Availability::Public => "public",
+            Availability::Unlisted => "unlisted",
+            Availability::Private => "private",
+            Availability::PremiumOnly => "premium_only",
+            Availability::SubscriberOnly => "subscriber_only",
+            Availability::NeedsAuth => "needs_auth",
+            Availability::Other(s) => s,
+        }
+    }
+}
+
+impl From<String> for Availability {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "public" => Availability::Public,
+            "unlisted" => Availability::Unlisted,
+            "private" => Availability::Private,
+            "premium_only" => Availability::PremiumOnly,
+            "subscriber_only" => Availability::SubscriberOnly,
+            "needs_auth" => Availability::NeedsAuth,
+            _ => Availability::Other(s),
+        }
+    }
+}
+
+impl From<Availability> for String {
+    fn from(availability: Availability) -> Self {
+        availability.as_str().to_owned()
+    }
+}
+
+/// One format a subtitle language is offered in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SubtitleTrack {
+    // Left out rather than written as `null` when missing, so `other` keeps what yt-dlp sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ext: Option<String>,
+    /// Missing when yt-dlp has the subtitles inline in `data`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// See `InfoDict::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
+/// Language code -> the formats its subtitles are offered in
+pub type Subtitles = HashMap<String, Vec<SubtitleTrack>>;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct Fragment {
-    //TODO: Perpetual update
     url: String,
     duration: f32,
+    /// See `InfoDict::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct Format {
-    //TODO: Perpetual update
     pub format_id: String,
     pub format_index: Option<String>,
     pub format_note: Option<String>,
@@ -144,47 +909,50 @@ pub struct Format {
     pub preference: Option<i32>,
     pub quality: Option<f32>,
     #[serde(default)]
-    pub has_drm: bool,
+    pub has_drm: DrmState,
     pub source_preference: Option<i32>,
     pub language_preference: Option<i32>,
     pub dynamic_range: Option<String>,
     pub container: Option<String>,
     pub downloader_options: Option<Map<String, Value>>,
+    /// See `InfoDict::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct Thumbnail {
-    //TODO: Perpetual update
     pub url: String,
     pub height: Option<usize>,
     pub width: Option<usize>,
     pub preference: Option<i32>,
     pub id: String,
     pub resolution: Option<String>,
+    /// Set by yt-dlp once the thumbnail is written to disk
+    pub filepath: Option<String>,
+    /// See `InfoDict::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct HeatPoint {
-    //TODO: Perpetual update
     start_time: f32,
     end_time: f32,
     value: f32,
+    /// See `InfoDict::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct Chapter {
     pub title: String,
     pub start_time: f32,
     pub end_time: f32,
+    /// See `InfoDict::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
-
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct InfoDict {
-    //TODO: Perpetual update
     pub age_limit: u8,
     pub abr: f32,
     pub asr: Option<f32>,
@@ -198,8 +966,8 @@ pub struct InfoDict {
     pub preference: Option<i32>,
     pub source_preference: i32,
     pub comment_count: Option<u32>,
-    #[serde(default)]
-    pub channel_follower_count: u32,
+    /// Missing for some extractors and videos, which leaves the stored count alone
+    pub channel_follower_count: Option<u32>,
     pub duration: u32,
     pub like_count: Option<u32>,
     pub playlist_index: Option<u32>,
@@ -209,13 +977,16 @@ pub struct InfoDict {
     pub __real_download: bool,
     #[serde(default)]
     pub channel_is_verified: bool,
-    pub has_drm: Value, //Either boolean or a string "maybe" fsfr
+    pub has_drm: DrmState,
     pub is_live: bool,
     pub playable_in_embed: bool,
     pub was_live: bool,
-    pub automatic_captions: Value,
-    pub release_timestamp: Value,
-    pub subtitles: Value,
+    #[serde(default)]
+    pub automatic_captions: Subtitles,
+    /// Unix seconds
+    pub release_timestamp: Option<i64>,
+    #[serde(default)]
+    pub subtitles: Subtitles,
     pub audio_channels: Option<usize>,
     pub filesize: Option<usize>,
     pub filetime: Option<usize>,
@@ -228,7 +999,7 @@ pub struct InfoDict {
     pub acodec: String,
     pub alt_title: Option<String>,
     pub audio_ext: String,
-    pub availability: String,
+    pub availability: Availability,
     pub channel: String,
     pub creator: Option<String>,
     pub chapters: Option<Vec<Chapter>>,
@@ -250,14 +1021,15 @@ pub struct InfoDict {
     pub format_note: String,
     pub id: String,
     pub language: Option<String>,
-    pub live_status: String,
+    pub live_status: LiveStatus,
     pub original_url: String,
     pub playlist: Option<String>,
     pub protocol: String,
     pub resolution: String,
     pub release_date: Option<String>,
     pub release_year: Option<u32>,
-    pub requested_subtitles: Option<String>,
+    /// Language code -> the track picked for download, with its `filepath` once written
+    pub requested_subtitles: Option<HashMap<String, SubtitleTrack>>,
     pub title: String,
     pub track: Option<String>,
     pub url: String,
@@ -286,13 +1058,14 @@ pub struct InfoDict {
     pub manifest_url: Option<String>,
     pub license: Option<String>,
     pub location: Option<String>,
+    /// Keys yt-dlp added since this was written, kept so they end up in `other` all the same. New ones get
+    /// reported once per run as schema drift
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
-
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct DownloadStatus {
-    //TODO: Perpetual update
-    pub status: String,
+    pub status: Status,
     pub info_dict: InfoDict,
     pub filename: String,
     pub tmpfilename: Option<String>,
@@ -318,4 +1091,128 @@ pub struct DownloadStatus {
     pub ctx_id: Option<usize>,
     pub max_progress: Option<f32>,
     pub progress_idx: Option<usize>,
+    /// Keys yt-dlp added since this was written, kept so they end up in `other` all the same
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+/// Adds the unknown keys in `extra` to `out` as paths under `prefix`
+fn unknown_keys(prefix: &str, extra: &Map<String, Value>, out: &mut BTreeSet<String>) {
+    out.extend(extra.keys().map(|k| format!("{}{}", prefix, k)));
+}
+
+impl InfoDict {
+    /// Paths of the keys yt-dlp sent that none of the structs know about, e.g. `formats[].fragments[].foo`
+    pub fn unknown_fields(&self) -> BTreeSet<String> {
+        let mut out = BTreeSet::new();
+        unknown_keys("", &self.extra, &mut out);
+        for format in &self.formats {
+            unknown_keys("formats[].", &format.extra, &mut out);
+            for fragment in format.fragments.iter().flatten() {
+                unknown_keys("formats[].fragments[].", &fragment.extra, &mut out);
+            }
+        }
+        for thumbnail in &self.thumbnails {
+            unknown_keys("thumbnails[].", &thumbnail.extra, &mut out);
+        }
+        for point in self.heatmap.iter().flatten() {
+            unknown_keys("heatmap[].", &point.extra, &mut out);
+        }
+        for chapter in self.chapters.iter().flatten() {
+            unknown_keys("chapters[].", &chapter.extra, &mut out);
+        }
+        for (field, subtitles) in [
+            ("subtitles", &self.subtitles),
+            ("automatic_captions", &self.automatic_captions),
+        ] {
+            for track in subtitles.values().flatten() {
+                unknown_keys(&format!("{}{{}}[].", field), &track.extra, &mut out);
+            }
+        }
+        for track in self.requested_subtitles.iter().flat_map(|r| r.values()) {
+            unknown_keys("requested_subtitles{}.", &track.extra, &mut out);
+        }
+        out
+    }
+}
+
+impl DownloadStatus {
+    /// Rebuilds what the progress hook saw from a tick and the video's info dict, with the format fields
+    /// of the stream the tick is about, the way yt-dlp fills them in for each stream it downloads
+    pub fn from_progress(progress: &Progress, info: &InfoDict) -> Self {
+        let mut info_dict = info.clone();
+        if let Some(format) = info
+            .formats
+            .iter()
+            .find(|f| f.format_id == progress.format_id)
+        {
+            info_dict.format_id = format.format_id.clone();
+            info_dict.format = format.format.clone();
+            info_dict.ext = format.ext.clone();
+            info_dict.vcodec = format.vcodec.clone();
+            info_dict.acodec = format.acodec.clone();
+            info_dict.protocol = format.protocol.clone();
+            info_dict.filesize = format.filesize;
+        }
+        info_dict.__real_download = progress.real_download;
+
+        DownloadStatus {
+            status: progress.status,
+            info_dict,
+            filename: progress.filename.clone(),
+            downloaded_bytes: progress.downloaded_bytes as usize,
+            total_bytes: progress.total_bytes.map(|b| b as usize),
+            total_bytes_estimate: progress.total_bytes_estimate,
+            elapsed: progress.elapsed.map(|e| e as f32),
+            eta: progress.eta.map(|e| e as f32),
+            speed: progress.speed.map(|s| s as f32),
+            fragment_index: progress.fragment_index.unwrap_or_default() as usize,
+            fragment_count: progress.fragment_count.unwrap_or_default() as usize,
+            ..Default::default()
+        }
+    }
+
+    /// File the master logs this stream to once it finishes, `<extractor_key>-<id>-<format_id>.json`. Anything but
+    /// ASCII letters, digits and `-_+.` becomes `_` and each part is cut to `LOG_NAME_PART_MAX`
+    pub fn log_name(&self) -> String {
+        let part = |s: &str| -> String {
+            s.chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '+' | '.' => c,
+                    _ => '_',
+                })
+                .take(LOG_NAME_PART_MAX)
+                .collect()
+        };
+        format!(
+            "{}-{}-{}.json",
+            part(&self.info_dict.extractor_key),
+            part(&self.info_dict.id),
+            part(&self.info_dict.format_id)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("4:05"), Ok(245));
+        assert_eq!(parse_duration("1:02:03"), Ok(3723));
+        assert_eq!(parse_duration("0:00"), Ok(0));
+        for bad in ["1:-5", "-5", "+5", "1:60", "1:75:00", "1::2", "1:2:3:4", "", "1.5", "4:05 min", "99999999999999999999"] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("2024-02-29"), Ok(20240229));
+        assert_eq!(parse_date("20001231"), Ok(20001231));
+        for bad in ["20241315", "2024-13-01", "2024-00-10", "2024-04-31", "2023-02-29", "1900-02-29", "20240100", "2024-1-15", "2024--0115", "+2024011", "2024-01-1a", "202401", ""] {
+            assert!(parse_date(bad).is_err(), "{:?}", bad);
+        }
+    }
 }
@@ -0,0 +1,118 @@
+use anyhow::{bail, Context, Result};
+use diesel::{
+    expression::Expression,
+    sql_query,
+    sql_types::{is_nullable, Bool, SqlType, Text},
+    sqlite::SqliteConnection,
+    QueryableByName, RunQueryDsl,
+};
+
+trait Nullability {
+    const NULLABLE: bool;
+}
+
+impl Nullability for is_nullable::IsNullable {
+    const NULLABLE: bool = true;
+}
+
+impl Nullability for is_nullable::NotNull {
+    const NULLABLE: bool = false;
+}
+
+fn is_nullable<C>() -> bool
+where
+    C: Expression,
+    C::SqlType: SqlType,
+    <C::SqlType as SqlType>::IsNull: Nullability,
+{
+    <<C::SqlType as SqlType>::IsNull as Nullability>::NULLABLE
+}
+
+struct ExpectedColumn {
+    name: &'static str,
+    nullable: bool,
+}
+
+/// Column list of a table in `schema.rs`, the list has to be complete and in order or this won't compile
+macro_rules! schema_table {
+    ($table:ident: $($col:ident),+ $(,)?) => {{
+        use crate::schema::$table;
+        let _: <$table::table as diesel::Table>::AllColumns = ($($table::$col,)+);
+        (
+            stringify!($table),
+            vec![$(ExpectedColumn {
+                name: <$table::$col as diesel::Column>::NAME,
+                nullable: is_nullable::<$table::$col>(),
+            }),+],
+        )
+    }};
+}
+
+#[derive(QueryableByName)]
+struct LiveColumn {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Bool)]
+    not_null: bool,
+}
+
+/// Compares the live DB against the schema compiled into this binary, so drift shows up at startup instead of as
+/// silently dropped columns or failed inserts halfway through a run
+pub fn check_schema(conn: &mut SqliteConnection) -> Result<()> {
+    let tables = vec![
+        schema_table!(videos: id, uid, link, title, author, duration, description, thumbnail_path, date, other, channel_id, album_id, track),
+        schema_table!(videos_duplicates: id, merged_into, uid, link, title, author, duration, description, thumbnail_path, date, other),
+        schema_table!(queue: id, uid, link, title, channel, position, status, attempts, last_error, worker_id, created_at, updated_at, error_kind, retry_at),
+        schema_table!(channels: id, uid, name, url, follower_count, verified),
+        schema_table!(albums: id, title, artist, release_year),
+        schema_table!(tags: id, name),
+        schema_table!(video_tags: video_id, tag_id),
+        schema_table!(categories: id, video_id, name),
+        schema_table!(chapters: id, video_id, position, title, start_time, end_time),
+        schema_table!(files: id, video_id, path, role, size, container, codec, format_id, checksum, mtime),
+    ];
+
+    let mut problems = Vec::new();
+    for (table, expected) in tables {
+        let live: Vec<LiveColumn> = sql_query(r#"SELECT "name", "notnull" AS "not_null" FROM pragma_table_info(?)"#)
+            .bind::<Text, _>(table)
+            .load(conn)
+            .with_context(|| format!("Unable to read columns of table {}", table))?;
+
+        if live.is_empty() {
+            problems.push(format!("table {} is missing", table));
+            continue;
+        }
+
+        for column in &expected {
+            match live.iter().find(|c| c.name == column.name) {
+                None => problems.push(format!("{}.{} is missing", table, column.name)),
+                Some(c) if c.not_null == column.nullable => problems.push(format!(
+                    "{}.{} is {} in the DB but {} in the schema",
+                    table,
+                    column.name,
+                    if c.not_null { "NOT NULL" } else { "nullable" },
+                    if column.nullable {
+                        "nullable"
+                    } else {
+                        "NOT NULL"
+                    },
+                )),
+                Some(_) => {}
+            }
+        }
+        for column in &live {
+            if !expected.iter().any(|c| c.name == column.name) {
+                problems.push(format!("{}.{} is not in the schema", table, column.name));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "Database schema doesn't match this build:\n  {}",
+            problems.join("\n  ")
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,7 @@
+//! What the master, the worker and the tests share: the protocol, the library's tables and how videos are recorded
+pub mod comms;
+pub mod library;
+pub mod links;
+pub mod models;
+pub mod rebuild;
+pub mod schema;
@@ -0,0 +1,386 @@
+use anyhow::Result;
+use diesel::{prelude::*, sqlite::SqliteConnection};
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::comms::{DownloadStatus, InfoDict, MovedFiles};
+use crate::links::VideoKey;
+use crate::models::{NewAlbum, NewCategory, NewChannel, NewChapter, NewFile, NewVideo, NewVideoTag};
+use crate::schema::{albums, categories, channels, chapters, files, tags, video_tags, videos};
+
+const SUBTITLE_EXTS: [&str; 7] = ["vtt", "srt", "ass", "ssa", "lrc", "ttml", "srv3"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileRole {
+    Audio,
+    Video,
+    Merged,
+    Thumbnail,
+    Subtitle,
+}
+
+impl FileRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileRole::Audio => "audio",
+            FileRole::Video => "video",
+            FileRole::Merged => "merged",
+            FileRole::Thumbnail => "thumbnail",
+            FileRole::Subtitle => "subtitle",
+        }
+    }
+}
+
+/// Adds the channel of `info`, bringing the stored one up to date with it instead if it exists and `update` is set.
+/// Returns its ID
+fn upsert_channel(conn: &mut SqliteConnection, info: &InfoDict, update: bool) -> QueryResult<Option<i64>> {
+    if info.channel_id.is_empty() {
+        return Ok(None);
+    }
+    // Names and follower counts change, keep the latest. A count yt-dlp didn't send is `None`, which the update skips
+    let channel = NewChannel {
+        uid: info.channel_id.clone(),
+        name: Some(info.channel.clone()),
+        url: Some(info.channel_url.clone()),
+        follower_count: info.channel_follower_count.map(Into::into),
+        verified: info.channel_is_verified,
+    };
+    if update {
+        diesel::insert_into(channels::table)
+            .values(&channel)
+            .on_conflict(channels::uid)
+            .do_update()
+            .set(&channel)
+            .execute(conn)?;
+    } else {
+        diesel::insert_or_ignore_into(channels::table)
+            .values(&channel)
+            .execute(conn)?;
+    }
+    channels::table
+        .filter(channels::uid.eq(&channel.uid))
+        .select(channels::id)
+        .first(conn)
+        .map(Some)
+}
+
+fn upsert_album(conn: &mut SqliteConnection, info: &InfoDict) -> QueryResult<Option<i64>> {
+    let Some(title) = info.album.clone() else {
+        return Ok(None);
+    };
+    let album = NewAlbum {
+        title,
+        artist: info.artist.clone().unwrap_or_default(),
+        release_year: info.release_year.map(i64::from),
+    };
+    diesel::insert_or_ignore_into(albums::table)
+        .values(&album)
+        .execute(conn)?;
+    albums::table
+        .filter(albums::title.eq(&album.title))
+        .filter(albums::artist.eq(&album.artist))
+        .select(albums::id)
+        .first(conn)
+        .map(Some)
+}
+
+fn insert_tags(conn: &mut SqliteConnection, video_id: i64, names: &[String]) -> QueryResult<usize> {
+    if names.is_empty() {
+        return Ok(0);
+    }
+    let new_tags: Vec<_> = names.iter().map(|n| tags::name.eq(n)).collect();
+    diesel::insert_or_ignore_into(tags::table)
+        .values(&new_tags)
+        .execute(conn)?;
+
+    let tag_ids: Vec<i64> = tags::table
+        .filter(tags::name.eq_any(names))
+        .select(tags::id)
+        .load(conn)?;
+    let links: Vec<_> = tag_ids
+        .into_iter()
+        .map(|tag_id| NewVideoTag { video_id, tag_id })
+        .collect();
+    diesel::insert_or_ignore_into(video_tags::table)
+        .values(&links)
+        .execute(conn)
+}
+
+/// Adds the tags, categories and chapters of `info` that `video_id` doesn't have yet, returning how many rows that
+/// took. Chapters only go to a video without any, they have nothing to tell them apart
+fn insert_details(conn: &mut SqliteConnection, video_id: i64, info: &InfoDict) -> QueryResult<usize> {
+    let mut inserted = insert_tags(conn, video_id, &info.tags)?;
+
+    let new_categories: Vec<_> = info
+        .categories
+        .iter()
+        .map(|name| NewCategory {
+            video_id,
+            name: name.clone(),
+        })
+        .collect();
+    inserted += diesel::insert_or_ignore_into(categories::table)
+        .values(&new_categories)
+        .execute(conn)?;
+
+    let has_chapters = diesel::select(diesel::dsl::exists(
+        chapters::table.filter(chapters::video_id.eq(video_id)),
+    ))
+    .get_result::<bool>(conn)?;
+    if !has_chapters {
+        let new_chapters: Vec<_> = info
+            .chapters
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, c)| NewChapter {
+                video_id,
+                position: i as i64,
+                title: Some(c.title.clone()),
+                start_time: c.start_time.into(),
+                end_time: c.end_time.into(),
+            })
+            .collect();
+        inserted += diesel::insert_into(chapters::table)
+            .values(&new_chapters)
+            .execute(conn)?;
+    }
+    Ok(inserted)
+}
+
+/// Writes a finished download and its channel, album, tags, categories and chapters in one transaction.
+/// Returns `false` if the video was already in the DB, in which case nothing is touched
+pub fn record_video(conn: &mut SqliteConnection, info: &InfoDict) -> Result<bool> {
+    conn.transaction(|conn| {
+        let video = NewVideo::from_info(info)?;
+        // Separate video and audio streams both report a real download, the first one wins
+        let exists = diesel::select(diesel::dsl::exists(
+            videos::table.filter(videos::uid.eq(&video.uid)),
+        ))
+        .get_result::<bool>(conn)?;
+        if exists {
+            return Ok(false);
+        }
+
+        let video = NewVideo {
+            channel_id: upsert_channel(conn, info, true)?,
+            album_id: upsert_album(conn, info)?,
+            ..video
+        };
+        debug!("Inserting video {:?}", video.uid);
+        diesel::insert_into(videos::table)
+            .values(&video)
+            .execute(conn)?;
+        let video_id: i64 = videos::table
+            .filter(videos::uid.eq(&video.uid))
+            .select(videos::id)
+            .first(conn)?;
+        insert_details(conn, video_id, info)?;
+        Ok(true)
+    })
+}
+
+/// Adds what a video that's already in the DB is missing from `info`: a channel or album it wasn't linked to, tags,
+/// categories and chapters. Its own columns are left alone, and so are channels and albums that exist already since
+/// `info` may be older than them. Returns whether the video gained anything
+pub fn record_metadata(conn: &mut SqliteConnection, video_id: i64, info: &InfoDict) -> Result<bool> {
+    conn.transaction(|conn| {
+        let channel_id = upsert_channel(conn, info, false)?;
+        let album_id = upsert_album(conn, info)?;
+        let mut changed = 0;
+        if channel_id.is_some() {
+            changed += diesel::update(videos::table.find(video_id).filter(videos::channel_id.is_null()))
+                .set(videos::channel_id.eq(channel_id))
+                .execute(conn)?;
+        }
+        if album_id.is_some() {
+            changed += diesel::update(videos::table.find(video_id).filter(videos::album_id.is_null()))
+                .set(videos::album_id.eq(album_id))
+                .execute(conn)?;
+        }
+        changed += insert_details(conn, video_id, info)?;
+        Ok(changed > 0)
+    })
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Describes `path` as it is on disk, `size` is only used if the file is gone (e.g. streams deleted after merging).
+/// The video ID is filled in once the files are inserted
+fn file_entry(path: &str, role: FileRole, size: Option<i64>) -> NewFile {
+    let metadata = fs::metadata(path).ok();
+    let checksum = metadata.as_ref().and_then(|_| {
+        sha256_file(Path::new(path))
+            .inspect_err(|e| warn!("Unable to checksum {}: {}", path, e))
+            .ok()
+    });
+    NewFile {
+        video_id: 0,
+        path: path.to_owned(),
+        role: role.as_str().to_owned(),
+        size: metadata.as_ref().map(|m| m.len() as i64).or(size),
+        container: Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_owned),
+        codec: None,
+        format_id: None,
+        checksum,
+        mtime: metadata
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64),
+    }
+}
+
+fn stream_entry(stream: &DownloadStatus, role: FileRole, moved: &MovedFiles) -> NewFile {
+    // Single-file downloads are moved to their final place, streams that got merged are deleted and keep the temp path
+    let path = moved
+        .moved
+        .get(&stream.filename)
+        .unwrap_or(&stream.filename);
+    let size = stream.total_bytes.unwrap_or(stream.downloaded_bytes) as i64;
+    let codec = match role {
+        FileRole::Audio => &stream.info_dict.acodec,
+        _ => &stream.info_dict.vcodec,
+    };
+    NewFile {
+        container: Some(stream.info_dict.ext.clone()),
+        codec: Some(codec.clone()),
+        format_id: Some(stream.info_dict.format_id.clone()),
+        ..file_entry(path, role, Some(size))
+    }
+}
+
+/// Files a finished download left on disk, stat'ed and checksummed but not yet tied to their video
+pub struct DownloadedFiles {
+    uid: String,
+    entries: Vec<NewFile>,
+    thumbnail_path: Option<String>,
+}
+
+/// Describes every file a finished download left on disk: the streams yt-dlp reported as finished, the merged output,
+/// the thumbnail and subtitles. Reads all of them to checksum them, so it's kept apart from the DB work
+pub fn describe_files(moved: &MovedFiles, video: Option<&DownloadStatus>, audio: Option<&DownloadStatus>) -> DownloadedFiles {
+    let mut entries = Vec::new();
+    if let Some(video) = video {
+        entries.push(stream_entry(video, FileRole::Video, moved));
+    }
+    if let Some(audio) = audio {
+        entries.push(stream_entry(audio, FileRole::Audio, moved));
+    }
+    // Merged from the two streams, or of streams we know nothing about, e.g. when replaying a quarantined payload
+    if video.is_some() == audio.is_some() {
+        let codec = match (&moved.vcodec, &moved.acodec) {
+            (Some(v), Some(a)) => Some(format!("{}+{}", v, a)),
+            (v, a) => v.clone().or(a.clone()),
+        };
+        entries.push(NewFile {
+            container: moved.ext.clone(),
+            codec,
+            format_id: moved.format_id.clone(),
+            ..file_entry(&moved.filepath, FileRole::Merged, None)
+        });
+    }
+
+    let thumbnails: Vec<&String> = [video, audio]
+        .into_iter()
+        .flatten()
+        .flat_map(|ds| {
+            ds.info_dict
+                .thumbnails
+                .iter()
+                .filter_map(|t| t.filepath.as_ref())
+        })
+        .collect();
+    let mut thumbnail_path = None;
+    for (old, new) in &moved.moved {
+        let ext = Path::new(new)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        if thumbnails.contains(&old) {
+            thumbnail_path = Some(new.clone());
+            entries.push(file_entry(new, FileRole::Thumbnail, None));
+        } else if SUBTITLE_EXTS.contains(&ext) {
+            entries.push(file_entry(new, FileRole::Subtitle, None));
+        }
+    }
+
+    DownloadedFiles {
+        uid: VideoKey::new(&moved.extractor_key, &moved.id).to_string(),
+        entries,
+        thumbnail_path,
+    }
+}
+
+/// Records described files under their video and points `videos.thumbnail_path` at the moved thumbnail
+pub fn insert_files(conn: &mut SqliteConnection, downloaded: DownloadedFiles) -> Result<usize> {
+    let DownloadedFiles {
+        uid,
+        mut entries,
+        thumbnail_path,
+    } = downloaded;
+    let Some(video_id) = videos::table
+        .filter(videos::uid.eq(&uid))
+        .select(videos::id)
+        .first::<i64>(conn)
+        .optional()?
+    else {
+        debug!("{} is not in the DB, not recording its files", uid);
+        return Ok(0);
+    };
+    for entry in &mut entries {
+        entry.video_id = video_id;
+    }
+
+    conn.transaction(|conn| {
+        let mut count = 0;
+        for entry in &entries {
+            count += diesel::insert_into(files::table)
+                .values(entry)
+                .on_conflict((files::video_id, files::path))
+                .do_update()
+                .set(entry)
+                .execute(conn)?;
+        }
+        if thumbnail_path.is_some() {
+            diesel::update(videos::table.find(video_id))
+                .set(videos::thumbnail_path.eq(thumbnail_path))
+                .execute(conn)?;
+        }
+        Ok(count)
+    })
+}
+
+/// Describes and records the files of a finished download in one go, for callers that aren't sharing the connection
+pub fn record_files(
+    conn: &mut SqliteConnection,
+    moved: &MovedFiles,
+    video: Option<&DownloadStatus>,
+    audio: Option<&DownloadStatus>,
+) -> Result<usize> {
+    insert_files(conn, describe_files(moved, video, audio))
+}
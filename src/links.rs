@@ -0,0 +1,267 @@
+use anyhow::{bail, Context, Result};
+use log::debug;
+use regex::{Captures, Regex};
+use std::{fmt, sync::LazyLock};
+
+/// Positional group used by the original default regex, kept for patterns without named groups
+const LEGACY_LINK_GROUP: usize = 5;
+
+/// Extractor name yt-dlp uses for YouTube and YouTube Music
+const YOUTUBE_EXTRACTOR: &str = "youtube";
+
+static YOUTUBE_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Za-z0-9_-]{11}$").unwrap());
+
+// Covers watch?v=, /shorts/, /embed/, /live/, /v/, youtu.be and music./m./nocookie hosts, with or without the scheme and host.
+// `[&;]` lets `v=` come after other query params, including HTML-escaped `&amp;` ones
+static YOUTUBE_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:https?://)?(?:(?:www|m|music)\.)?(?:(?:(?:youtube(?:-nocookie)?\.com)?/)?(?:watch\?(?:[^#]*[&;])?v=|shorts/|embed/|live/|v/|e/)|youtu\.be/)(?P<id>[A-Za-z0-9_-]{11})(?:[^A-Za-z0-9_-]|$)",
+    )
+    .unwrap()
+});
+
+// What the default regex captures: an ID followed by leftovers like `&list=...` or `?t=42`
+static YOUTUBE_ID_PREFIX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(?P<id>[A-Za-z0-9_-]{11})(?:[^A-Za-z0-9_-]|$)").unwrap());
+
+// Playlists, channels (by ID, legacy name or handle) and their tabs, these get flat-extracted into videos first
+static COLLECTION_LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:[?&;]list=|/playlist\b|/channel/|/c/|/user/|/@[^/?#]+|/browse/)").unwrap());
+
+/// Whether `link` points at a playlist or channel rather than a single video
+pub fn is_collection(link: &str) -> bool {
+    VideoKey::normalize(link).is_none() && COLLECTION_LINK.is_match(link)
+}
+
+/// Canonical identity of a video, in the same `"<extractor> <id>"` form yt-dlp uses for its download archive.
+/// This is what gets stored in `videos.uid`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VideoKey {
+    pub extractor: String,
+    pub id: String,
+}
+
+impl VideoKey {
+    pub fn new(extractor: &str, id: &str) -> Self {
+        VideoKey {
+            extractor: extractor.to_lowercase(),
+            id: id.to_owned(),
+        }
+    }
+
+    pub fn youtube(id: &str) -> Self {
+        VideoKey::new(YOUTUBE_EXTRACTOR, id)
+    }
+
+    /// Parses a stored key, bare 11-char IDs written by older versions are treated as YouTube
+    pub fn parse(uid: &str) -> Option<Self> {
+        match uid.split_once(' ') {
+            Some((extractor, id)) if !extractor.is_empty() && !id.is_empty() => Some(VideoKey::new(extractor, id)),
+            Some(_) => None,
+            None if YOUTUBE_ID.is_match(uid) => Some(VideoKey::youtube(uid)),
+            None => None,
+        }
+    }
+
+    /// Turns whatever a link source captured (full URL, short link, bare ID) into a key
+    pub fn normalize(link: &str) -> Option<Self> {
+        let link = link.trim();
+        YOUTUBE_LINK
+            .captures(link)
+            .or_else(|| YOUTUBE_ID_PREFIX.captures(link))
+            .map(|caps| VideoKey::youtube(&caps["id"]))
+    }
+
+    /// Link handed to yt-dlp, stripped of playlist and tracking params
+    pub fn url(&self) -> Option<String> {
+        match self.extractor.as_str() {
+            YOUTUBE_EXTRACTOR => Some(format!("https://www.youtube.com/watch?v={}", self.id)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VideoKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.extractor, self.id)
+    }
+}
+
+/// Which part of a match holds the link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkGroup {
+    /// `(?P<id>...)` and/or `(?P<url>...)`, `id` wins when both participate in a match
+    Named,
+    /// Group 5, for patterns written before named groups were supported
+    Legacy,
+}
+
+/// Pulls video links out of arbitrary text with a user-supplied regex
+#[derive(Debug, Clone)]
+pub struct LinkExtractor {
+    regex: Regex,
+    group: LinkGroup,
+}
+
+impl LinkExtractor {
+    /// Compiles `pattern` and checks that it has somewhere to take the link from
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).with_context(|| format!("Invalid link regex {:?}", pattern))?;
+
+        let has_named = regex
+            .capture_names()
+            .flatten()
+            .any(|name| name == "id" || name == "url");
+
+        let group = if has_named {
+            LinkGroup::Named
+        } else if regex.captures_len() > LEGACY_LINK_GROUP {
+            debug!(
+                "Link regex has no `id`/`url` groups, falling back to group {}",
+                LEGACY_LINK_GROUP
+            );
+            LinkGroup::Legacy
+        } else {
+            bail!(
+                "Link regex {:?} has no named group `id` or `url` and only {} capture groups, expected (?P<id>...), (?P<url>...) or at least {}",
+                pattern,
+                regex.captures_len() - 1,
+                LEGACY_LINK_GROUP
+            );
+        };
+
+        Ok(LinkExtractor { regex, group })
+    }
+
+    fn link_from<'h>(&self, caps: &Captures<'h>) -> Option<&'h str> {
+        match self.group {
+            LinkGroup::Named => caps.name("id").or_else(|| caps.name("url")),
+            LinkGroup::Legacy => caps.get(LEGACY_LINK_GROUP),
+        }
+        .map(|m| m.as_str())
+    }
+
+    /// Returns every link found in `haystack`, in order of appearance
+    pub fn extract(&self, haystack: &str) -> Vec<String> {
+        self.regex
+            .captures_iter(haystack)
+            .filter_map(|caps| {
+                let link = self.link_from(&caps);
+                if link.is_none() {
+                    debug!(
+                        "Match {:?} has no link group, skipping",
+                        caps.get(0).map(|m| m.as_str())
+                    );
+                }
+                link.map(str::to_owned)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "see https://www.youtube.com/watch?v=Xq1Hm3t7bKc and watch?v=dQw4w9WgXcQ&t=42 later";
+
+    #[test]
+    fn named_groups() {
+        let by_id = LinkExtractor::new(r"watch\?v=(?P<id>[\w-]{11})").unwrap();
+        assert_eq!(by_id.extract(TEXT), ["Xq1Hm3t7bKc", "dQw4w9WgXcQ"]);
+
+        let by_url = LinkExtractor::new(r"(?P<url>https://\S+)").unwrap();
+        assert_eq!(by_url.extract(TEXT), ["https://www.youtube.com/watch?v=Xq1Hm3t7bKc"]);
+
+        // `id` wins when both take part, `url` fills in when only it does
+        let both = LinkExtractor::new(r"(?P<url>https://\S+v=(?P<id>[\w-]{11}))|(?P<bare>watch\?v=[\w-]{11})").unwrap();
+        assert_eq!(both.extract(TEXT), ["Xq1Hm3t7bKc"]);
+        let either = LinkExtractor::new(r"https://\S+v=(?P<id>[\w-]{11})|(?P<url>watch\?v=[\w-]{11})").unwrap();
+        assert_eq!(either.extract(TEXT), ["Xq1Hm3t7bKc", "watch?v=dQw4w9WgXcQ"]);
+    }
+
+    #[test]
+    fn legacy_positional_group() {
+        // The default pattern before named groups, the link is group 5
+        let legacy = LinkExtractor::new(r"(https://(music)|(www)\.youtube\.com/)?(watch\?v=)([a-zA-Z0-9/\.\?=\-_]+)").unwrap();
+        assert_eq!(legacy.extract(TEXT), ["Xq1Hm3t7bKc", "dQw4w9WgXcQ"]);
+    }
+
+    #[test]
+    fn nowhere_to_take_the_link_from() {
+        let e = LinkExtractor::new(r"(watch)\?v=([\w-]{11})").unwrap_err();
+        assert!(e.to_string().contains("only 2 capture groups"), "{}", e);
+        // Other names don't count as link groups
+        assert!(LinkExtractor::new(r"watch\?v=(?P<video>[\w-]{11})").is_err());
+        assert!(LinkExtractor::new(r"watch\?v=(").is_err());
+    }
+
+    #[test]
+    fn video_links() {
+        let key = VideoKey::youtube("Xq1Hm3t7bKc");
+        for link in [
+            "https://www.youtube.com/watch?v=Xq1Hm3t7bKc",
+            "http://youtube.com/watch?feature=share&v=Xq1Hm3t7bKc",
+            "https://m.youtube.com/watch?app=desktop&amp;v=Xq1Hm3t7bKc",
+            "https://music.youtube.com/watch?v=Xq1Hm3t7bKc&si=abc",
+            "https://youtu.be/Xq1Hm3t7bKc?t=42",
+            "https://www.youtube.com/shorts/Xq1Hm3t7bKc",
+            "https://www.youtube-nocookie.com/embed/Xq1Hm3t7bKc",
+            "youtube.com/live/Xq1Hm3t7bKc",
+            "  Xq1Hm3t7bKc  ",
+            // What the default regex leaves over
+            "Xq1Hm3t7bKc&list=PLrAXtmErZgOeiKm4sgNOknGvNjby9efdf",
+        ] {
+            assert_eq!(VideoKey::normalize(link).as_ref(), Some(&key), "{}", link);
+        }
+        // A video in a playlist is still that video
+        let in_playlist = "https://www.youtube.com/watch?v=Xq1Hm3t7bKc&list=PLrAXtmErZgOeiKm4sgNOknGvNjby9efdf&index=3";
+        assert_eq!(VideoKey::normalize(in_playlist).as_ref(), Some(&key));
+        assert!(!is_collection(in_playlist));
+
+        assert_eq!(key.url().as_deref(), Some("https://www.youtube.com/watch?v=Xq1Hm3t7bKc"));
+    }
+
+    #[test]
+    fn not_video_links() {
+        for link in [
+            "https://www.youtube.com/watch?v=short",
+            "https://www.youtube.com/watch?vv=Xq1Hm3t7bKc",
+            "https://www.youtube.com/playlist?list=PLrAXtmErZgOeiKm4sgNOknGvNjby9efdf",
+            "https://vimeo.com/76979871",
+            "Xq1Hm3t7bK",
+            "",
+        ] {
+            assert_eq!(VideoKey::normalize(link), None, "{}", link);
+        }
+    }
+
+    #[test]
+    fn collections() {
+        for link in [
+            "https://www.youtube.com/playlist?list=PLrAXtmErZgOeiKm4sgNOknGvNjby9efdf",
+            "https://music.youtube.com/playlist?list=OLAK5uy_k",
+            "https://music.youtube.com/browse/MPREb_abc",
+            "https://www.youtube.com/channel/UCuAXFkgsw1L7xaCfnd5JJOw/videos",
+            "https://www.youtube.com/c/Harbour",
+            "https://www.youtube.com/user/harbour",
+            "https://www.youtube.com/@harbour",
+            "https://www.youtube.com/@harbour/streams",
+        ] {
+            assert!(is_collection(link), "{}", link);
+            assert_eq!(VideoKey::normalize(link), None, "{}", link);
+        }
+        assert!(!is_collection("https://www.youtube.com/watch?v=Xq1Hm3t7bKc"));
+        assert!(!is_collection("https://vimeo.com/76979871"));
+    }
+
+    #[test]
+    fn stored_keys() {
+        assert_eq!(VideoKey::parse("youtube Xq1Hm3t7bKc"), Some(VideoKey::youtube("Xq1Hm3t7bKc")));
+        assert_eq!(VideoKey::parse("Vimeo 76979871"), Some(VideoKey::new("vimeo", "76979871")));
+        // Written by versions that only knew YouTube
+        assert_eq!(VideoKey::parse("Xq1Hm3t7bKc"), Some(VideoKey::youtube("Xq1Hm3t7bKc")));
+        assert_eq!(VideoKey::parse("youtube "), None);
+        assert_eq!(VideoKey::parse("not-a-key"), None);
+        assert_eq!(VideoKey::new("Youtube", "Xq1Hm3t7bKc").to_string(), "youtube Xq1Hm3t7bKc");
+    }
+}
@@ -1,35 +1,55 @@
-mod comms;
-mod models;
-mod schema;
+mod db;
+mod page;
+mod quarantine;
+mod queue;
+mod report;
+mod retry;
+mod search;
+mod sources;
+mod workers;
+
+use rhytm::{comms, library, links, models, rebuild, schema};
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use comms::{DownloadStatus, Message, MessageRead, MessageWrite};
 use core::result::Result::Ok;
-use diesel::{query_dsl::methods::SelectDsl, sqlite, Connection, RunQueryDsl, SelectableHelper};
+use diesel::{query_dsl::methods::SelectDsl, sqlite::SqliteConnection, Connection, RunQueryDsl};
 use diesel_migrations::MigrationHarness;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations};
-use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressStyle};
 use indicatif_log_bridge::LogWrapper;
-use log::{debug, info, log, warn};
-use models::Video;
-use regex::Regex;
+use links::{LinkExtractor, VideoKey};
+use log::{info, warn};
+use queue::LinkQueue;
+use retry::RetryPolicy;
+use schema::videos;
 use simplelog::{error, CombinedLogger, Config, TermLogger, TerminalMode};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{
+    collections::HashSet,
     env,
     fs::{self, Permissions},
     io::ErrorKind,
-    os::unix::{fs::PermissionsExt, net::UnixListener},
-    process::Command,
+    os::unix::fs::PermissionsExt,
+    path::Path,
     sync::{Arc, Mutex},
 };
-use tokio::task::JoinHandle;
+use tokio::{
+    net::UnixListener,
+    signal::unix::{signal, SignalKind},
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
 
-use crate::comms::Options;
-use crate::models::NewVideo;
+use crate::comms::{DownloadOptions, InputFormat, Options};
+use crate::workers::{Arrivals, Shared, Spawner, Stop};
 
 pub const EMBEDDED_MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+/// Wait after the first failed accept, doubled for every failure in a row up to `ACCEPT_BACKOFF_MAX`
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+/// Failed accepts in a row after which no worker is expected to get through anymore, e.g. out of file descriptors
+const ACCEPT_FAILURES_MAX: u32 = 12;
 
 fn ensure_dir(dir: &str) -> Result<(), std::io::Error> {
     if let Err(e) = fs::create_dir_all(dir) {
@@ -49,13 +69,33 @@ fn ensure_no_file(file: &str) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Opens `links.db` in `download_dir`, bringing it up to date
+fn open_library(download_dir: &str) -> Result<SqliteConnection> {
+    let path = download_dir.to_string() + "/links.db";
+    let mut connection = SqliteConnection::establish(&path).with_context(|| format!("Unable to open {}", path))?;
+    connection
+        .run_pending_migrations(EMBEDDED_MIGRATIONS)
+        .map_err(|e| anyhow::anyhow!("Unable to migrate {}: {}", path, e))?;
+    db::check_schema(&mut connection)?;
+    Ok(connection)
+}
+
+/// Renames `links.db` in `download_dir` out of the way, if there is one
+fn set_library_aside(download_dir: &str) -> Result<()> {
+    let path = download_dir.to_string() + "/links.db";
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    let aside = format!("{}.{}.bak", path, queue::unix_now());
+    fs::rename(&path, &aside).with_context(|| format!("Unable to move {} aside", path))?;
+    warn!("Moved {} to {}", path, aside);
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    use self::schema::videos::dsl::*;
     let options = Options::parse();
 
-    let logs_dir = options.download_dir.clone() + &options.logs_dir_relative;
-
     let logger = CombinedLogger::new(vec![TermLogger::new(
         options.verbosity,
         Config::default(),
@@ -63,11 +103,6 @@ async fn main() -> Result<()> {
         simplelog::ColorChoice::Auto,
     )]);
 
-    let pb_style = Arc::new(
-        ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7}, {bytes_per_sec} {msg:>}")
-            .unwrap()
-            .progress_chars("##-"),
-    );
     let mp: Arc<Mutex<MultiProgress>> = Arc::new(Mutex::new(MultiProgress::new()));
 
     LogWrapper::new(Arc::clone(&mp).lock().unwrap().to_owned(), logger)
@@ -76,56 +111,114 @@ async fn main() -> Result<()> {
 
     log::set_max_level(log::LevelFilter::Trace);
 
-    let soup = fs::read_to_string(options.html_path.clone())?;
-    debug!("Read {} bytes from {}", soup.len(), options.html_path);
+    match &options.command {
+        comms::Command::Download(args) => download(&options, args, mp).await,
+        comms::Command::List(query_options) => {
+            let entries = search::query(
+                &mut open_library(&options.download_dir)?,
+                None,
+                query_options,
+            )?;
+            search::print(&entries, query_options.output)
+        }
+        comms::Command::ReplayQuarantine => {
+            let logs_dir = options.download_dir.clone() + &options.logs_dir_relative;
+            quarantine::replay(
+                &mut open_library(&options.download_dir)?,
+                &quarantine::dir(&logs_dir),
+            )
+        }
+        comms::Command::RebuildDb { fresh } => {
+            if *fresh {
+                set_library_aside(&options.download_dir)?;
+            }
+            let mut connection = open_library(&options.download_dir).context("Pass --fresh to rebuild from scratch if the DB is corrupt")?;
+            let logs_dir = options.download_dir.clone() + &options.logs_dir_relative;
+            rebuild::rebuild(&mut connection, Path::new(&logs_dir))?;
+            Ok(())
+        }
+        comms::Command::Search {
+            query,
+            query_options,
+        } => {
+            let entries = search::query(
+                &mut open_library(&options.download_dir)?,
+                Some(query),
+                query_options,
+            )?;
+            search::print(&entries, query_options.output)
+        }
+    }
+}
+
+async fn download(options: &Options, args: &DownloadOptions, mp: Arc<Mutex<MultiProgress>>) -> Result<()> {
+    let started = Instant::now();
+    let started_at = queue::unix_now();
+    let logs_dir = options.download_dir.clone() + &options.logs_dir_relative;
 
-    let regex = Regex::new(&options.parse_regex_str).unwrap(); //TODO?: parse this from args
+    let pb_style = ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7}, {bytes_per_sec} {msg:>}")
+        .unwrap()
+        .progress_chars("##-");
+
+    let extractor = LinkExtractor::new(&args.parse_regex_str)?;
+
+    let input = sources::read_input(&args.input_path)?;
+    let input_format = match args.input_format {
+        InputFormat::Auto => sources::detect_format(&args.input_path, &input),
+        f => f,
+    };
+    info!("Reading {} as {:?}", args.input_path, input_format);
 
     // Ensure that all directories exist
     ensure_dir(&logs_dir).unwrap();
-    ensure_dir(&options.tmp_dir).unwrap();
+    ensure_dir(&args.tmp_dir).unwrap();
     ensure_dir(&options.download_dir).unwrap();
-    let mut connection = sqlite::SqliteConnection::establish(&(options.download_dir.to_string() + "/links.db")).unwrap();
+    let mut connection = open_library(&options.download_dir)?;
 
-    connection
-        .run_pending_migrations(EMBEDDED_MIGRATIONS)
-        .unwrap();
-
-    let downloaded_videos: Vec<String> = videos
-        .select(Video::as_select())
-        .load(&mut connection)
-        .unwrap()
+    let downloaded_videos: HashSet<VideoKey> = videos::table
+        .select(videos::uid)
+        .load::<String>(&mut connection)
+        .context("Unable to read the downloaded videos")?
         .iter()
-        .map(|x: &Video| x.uid.clone())
+        .filter_map(|uid| VideoKey::parse(uid))
         .collect();
 
-    let links_raw = regex
-        .captures_iter(&soup)
-        .map(|x| {
-            x.get(5)
-                .expect("Unable to find video link capture")
-                .as_str()
-                .to_owned()
-                .clone()
-        })
-        .collect::<Vec<String>>();
+    let links_raw = sources::source_for(input_format, extractor).links(&input)?;
 
-    let len_raw = links_raw.len();
+    let resumed = queue::reset_interrupted(&mut connection)?;
+    if resumed > 0 {
+        info!(
+            "Resuming {} downloads interrupted in a previous run",
+            resumed
+        );
+    }
 
-    let links = Box::leak(Box::new(Vec::<String>::new()));
+    if args.recheck {
+        let rechecked = queue::recheck(&mut connection)?;
+        info!(
+            "Rechecking {} links that failed permanently before",
+            rechecked
+        );
+    }
 
-    for l in links_raw {
-        if !downloaded_videos.contains(&l) {
-            links.push(l.clone());
-        }
+    let mut queue = LinkQueue::new(downloaded_videos);
+    for entry in links_raw {
+        queue.push(&mut connection, entry)?;
     }
 
     info!(
-        "Found {} links, {} found in the DB",
-        len_raw,
-        downloaded_videos.len()
+        "Found {} links, {} already in the DB, {} queued, {} pending in total",
+        queue.found,
+        queue.in_db,
+        queue.queued,
+        queue::pending_count(&mut connection)?
     );
-    let batches = Arc::new(Mutex::new(links.chunks(options.link_batch_size)));
+    if queue.abandoned > 0 {
+        warn!(
+            "Skipping {} links that failed permanently before, pass --recheck to try them again",
+            queue.abandoned
+        );
+    }
 
     // finding client exe
 
@@ -149,207 +242,151 @@ async fn main() -> Result<()> {
             .expect("Unable to set permissions, exiting");
     }
 
-
-
-    ensure_no_file(&(options.tmp_dir.clone() + "/master.sock")).unwrap();
-    let listener = UnixListener::bind(options.tmp_dir.clone() + "/master.sock")?;
-
-    let mut handles = Vec::<(JoinHandle<()>, usize)>::with_capacity(options.threads);
-
-    for thr_id in 0..options.threads {
-        let msp = options.tmp_dir.clone() + "/master.sock";
-
-        ensure_dir(&(options.tmp_dir.clone() + "/" + &thr_id.to_string())).unwrap();
-
-        // Spawn child
-        let _client = Command::new(thread_path.clone())
-            // .env_clear()
-            .env("MSP", msp)
-            .env("THR_ID", thr_id.to_string())
-            .env("LOG_DIR", logs_dir.clone())
-            .env("DOWNLOAD_DIR", options.download_dir.clone())
-            .env("TMP_DIR", options.tmp_dir.clone())
-            .env(
+    // Registered before any worker exists, so an early Ctrl-C can't kill the master outright and orphan them
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut pause = signal(SignalKind::user_defined1())?;
+    let mut resume = signal(SignalKind::user_defined2())?;
+
+    ensure_no_file(&(args.tmp_dir.clone() + "/master.sock")).unwrap();
+    let listener = UnixListener::bind(args.tmp_dir.clone() + "/master.sock")?;
+
+    let spawner = Arc::new(Spawner {
+        exe: thread_path,
+        env: vec![
+            ("MSP", args.tmp_dir.clone() + "/master.sock"),
+            ("LOG_DIR", logs_dir.clone()),
+            ("DOWNLOAD_DIR", options.download_dir.clone()),
+            ("TMP_DIR", args.tmp_dir.clone()),
+            ("MAX_FRAME_SIZE", args.max_frame_size.to_string()),
+            (
                 "YT_DLP_OUTPUT_TEMPLATE",
-                options.yt_dlp_output_template.clone(),
-            )
-            .spawn()
-            .with_context(|| format!("Unable to spawn thread {}", thr_id))?;
-    }
-    //now we should have all our threads running and we should try to accept the conns
-
-    let connection = Arc::new(Mutex::new(connection));
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                let batches = Arc::clone(&batches);
-                let connection = Arc::clone(&connection);
-                let pb_style = Arc::clone(&pb_style);
-                let msg = stream.read_json_msg::<Message>().unwrap();
-                let mp = Arc::clone(&mp);
-                let mut audio_ds: DownloadStatus = Default::default();
-                let mut video_ds: DownloadStatus = Default::default();
-
-                if std::mem::discriminant(&msg) != std::mem::discriminant(&Message::Greeting(0)) {
-                    error!("Received non-greeting message from client: {:?}", msg);
-                    error!(
-                        "Discriminants: Client: {:?}, Greeting(0): {:?}",
-                        std::mem::discriminant(&msg),
-                        std::mem::discriminant(&Message::Greeting(0))
-                    );
-                    panic!("Client sent garbage");
+                args.yt_dlp_output_template.clone(),
+            ),
+        ],
+        groups: Mutex::default(),
+    });
+    let shared = Arc::new(Shared {
+        connection: Mutex::new(connection),
+        queue: Mutex::new(queue),
+        summary: Mutex::new(report::RunSummary::default()),
+        mp,
+        pb_style,
+        logs_dir,
+        batch_size: args.link_batch_size,
+        max_frame_size: args.max_frame_size,
+        inactivity_timeout: Duration::from_secs(args.inactivity_timeout),
+        retry: RetryPolicy {
+            max_attempts: args.max_attempts.max(1),
+            backoff: Duration::from_secs(args.retry_backoff),
+            jitter: args.retry_jitter.clamp(0.0, 1.0),
+        },
+        strict_schema: args.strict_schema,
+        stop: watch::Sender::new(Stop::Running),
+        paused: watch::Sender::new(false),
+        controls: Mutex::default(),
+    });
+
+    // The first signal drains the workers, the second interrupts their downloads and kills the workers that don't stop
+    // in time, and a third kills them all right away and exits
+    let signals = {
+        let shared = Arc::clone(&shared);
+        let spawner = Arc::clone(&spawner);
+        let socket = args.tmp_dir.clone() + "/master.sock";
+        tokio::spawn(async move {
+            for stop in [Stop::Draining, Stop::Killing] {
+                tokio::select! {
+                    _ = interrupt.recv() => {}
+                    _ = terminate.recv() => {}
                 }
-
-                let thr_id: usize;
-                if let Message::Greeting(v) = msg {
-                    thr_id = v;
-                } else {
-                    panic!("How the fuck are we getting here?");
+                match stop {
+                    Stop::Draining => warn!("Stopping once the current downloads finish, interrupt again to interrupt them"),
+                    _ => warn!("Interrupting the current downloads"),
                 }
-
-                stream.write_json_msg(&msg).unwrap();
-
-                let pb = ProgressBar::new_spinner();
-                pb.set_length(10000);
-                pb.enable_steady_tick(Duration::from_millis(25));
-                mp.lock().unwrap().add(pb.clone());
-
-                let logs_dir = logs_dir.clone();
-
-                let handle = tokio::spawn(async move {
-                    debug!("Thread {:?} functional", thr_id);
-                    loop {
-                        let logs_dir = logs_dir.clone();
-                        match stream.read_json_msg::<Message>().unwrap() {
-                            // Batch request
-                            Message::BatchRequest => {
-                                debug!("got BatchRequest from socket {:?}", thr_id);
-                                match batches.lock().unwrap().next() {
-                                    Some(batch) => {
-                                        let batch = &Message::Batch(batch.to_vec());
-
-                                        debug!("Sending Batch({:?}) to thread {:?}", batch, thr_id);
-                                        stream
-                                            .write_json_msg(&batch)
-                                            .with_context(|| format!("Unable to send Batch to thread {}", thr_id))
-                                            .unwrap();
-                                    }
-                                    None => {
-                                        debug!("No batches left, sending EndRequest to thread {:?}", thr_id);
-                                        stream
-                                            .write_json_msg(&Message::EndRequest)
-                                            .expect(&format!("Unable to send EndRequest to thread {:?}", thr_id));
-                                        return;
-                                    }
-                                };
-                            }
-
-                            // Log
-                            Message::Log {
-                                thr_id: _,
-                                level,
-                                target,
-                                msg,
-                            } => {
-                                debug!("got Log message from socket {:?}", thr_id);
-
-                                log!(target: &target, level, "{}", msg);
-                            }
-
-                            // JSON
-                            Message::JSON(msg) => {
-                                let json: DownloadStatus = serde_json::from_str(&msg).unwrap_or_else(|e| {
-                                    std::fs::write(logs_dir.clone() + "fucked.json", &msg).unwrap();
-                                    error!(
-                                        "Parse failed @ {}:{}, message is {}",
-                                        e.line(),
-                                        e.column(),
-                                        e
-                                    );
-                                    error!("Context: {}", &msg[e.column() - 20..e.column() + 20]);
-                                    error!("                           ^");
-                                    error!("                           \\-- Error is here");
-                                    panic!("Fucking json")
-                                });
-                                pb.set_message(format!(
-                                    "{} - {} [{}]",
-                                    json.info_dict
-                                        .creator
-                                        .clone()
-                                        .unwrap_or(json.info_dict.uploader.clone()),
-                                    json.info_dict.title.clone(),
-                                    json.info_dict.display_id.clone()
-                                ));
-                                pb.set_length(
-                                    json.total_bytes
-                                        .unwrap_or(json.total_bytes_estimate.unwrap_or(0.0) as usize) as u64,
-                                );
-                                pb.set_position(json.downloaded_bytes as u64);
-
-                                if json.status == "finished" {
-                                    std::fs::write(
-                                        format!("{}/{}.json", logs_dir, json.filename.replace("/", "_")),
-                                        &serde_json::to_string_pretty(&json).unwrap(),
-                                    )
-                                    .expect("Unable to write json");
-                                    if json.info_dict.acodec == "none" {
-                                        video_ds = json.clone();
-                                    }
-                                    if json.info_dict.vcodec == "none" {
-                                        audio_ds = json.clone();
-                                    }
-                                    if json.info_dict.__real_download {
-                                        let video_repr = NewVideo {
-                                            title: Some(json.info_dict.title),
-                                            author: json.info_dict.artist,
-                                            duration: Some(json.info_dict.duration.into()),
-                                            description: Some(json.info_dict.description),
-                                            uid: json.info_dict.display_id.clone(),
-                                            link: Some(json.info_dict.webpage_url),
-                                        };
-                                        debug!("Inserting video {:?}", video_repr);
-                                        let inserted_rows = diesel::insert_into(videos)
-                                            .values(video_repr)
-                                            .execute(&mut *connection.lock().unwrap())
-                                            .unwrap();
-                                        pb.set_style(ProgressStyle::default_spinner());
-                                    }
-                                }
-                                //TODO: get the json model from wherever and parse it like that instead of stupid "Value" parsing
-
-                                //TODO! parse the fucking JSON, *insert approximately six hours of selfharm*
-                            }
-                            Message::DownloadStart => {
-                                pb.set_style(pb_style.as_ref().clone());
-                                pb.tick()
-                            }
-                            Message::DownloadEnd => {}
-                            Message::EndRequest => {
-                                unimplemented!("Unexpected EndRequest recieved from socket {:?}", thr_id)
-                            }
-                            Message::Batch(_) => {
-                                unimplemented!("Unexpected Batch recieved from socket {:?}", thr_id)
+                shared.set_stop(stop);
+            }
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+            error!("Exiting without waiting for the workers");
+            spawner.kill_all();
+            let _ = ensure_no_file(&socket);
+            std::process::exit(130);
+        })
+    };
+    // SIGUSR1 holds the downloads where they are, e.g. to free up the connection for a while, and SIGUSR2 resumes them
+    let pausing = {
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            loop {
+                let paused = tokio::select! {
+                    _ = pause.recv() => true,
+                    _ = resume.recv() => false,
+                };
+                match paused {
+                    true => warn!("Pausing the downloads, send SIGUSR2 to resume them"),
+                    false => warn!("Resuming the downloads"),
+                }
+                shared.set_paused(paused);
+            }
+        })
+    };
+    let arrivals = Arrivals::default();
+
+    // Workers are matched to their slot by the thread ID in their greeting, replacements included. Accepting stops
+    // once every slot is done, since nothing is left to connect then
+    let (stop_accepting, mut stopped) = oneshot::channel::<()>();
+    let accept = {
+        let arrivals = Arc::clone(&arrivals);
+        let shared = Arc::clone(&shared);
+        let max_frame_size = args.max_frame_size;
+        tokio::spawn(async move {
+            let mut failures = 0;
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            failures = 0;
+                            tokio::spawn(workers::greet(stream, Arc::clone(&arrivals), max_frame_size));
+                        }
+                        // Errors like EMFILE don't go away by trying again right away
+                        Err(e) => {
+                            failures += 1;
+                            error!("Unable to accept a worker connection: {}", e);
+                            if failures == ACCEPT_FAILURES_MAX {
+                                error!("Failed to accept {} worker connections in a row, stopping the run", failures);
+                                shared.set_stop(Stop::Draining);
+                                return;
                             }
-                            Message::Greeting(_) => {
-                                unimplemented!("Unexpected Greeting recieved from socket {:?}", thr_id)
+                            let backoff = ACCEPT_BACKOFF.saturating_mul(1 << (failures - 1)).min(ACCEPT_BACKOFF_MAX);
+                            tokio::select! {
+                                _ = tokio::time::sleep(backoff) => {}
+                                _ = &mut stopped => return,
                             }
                         }
-                    }
-                });
-                handles.push((handle, thr_id));
-                info!("Spawned thread {}", 0);
+                    },
+                    _ = &mut stopped => return,
+                }
             }
-            Err(_) => todo!(),
-        }
+        })
+    };
+
+    let mut handles = Vec::<(JoinHandle<()>, usize)>::with_capacity(args.threads);
+    for thr_id in 0..args.threads {
+        ensure_dir(&(args.tmp_dir.clone() + "/" + &thr_id.to_string())).unwrap();
+        handles.push((
+            tokio::spawn(workers::supervise(
+                thr_id,
+                Arc::clone(&spawner),
+                Arc::clone(&arrivals),
+                Arc::clone(&shared),
+                args.max_respawns,
+            )),
+            thr_id,
+        ));
+        info!("Spawned thread {}", thr_id);
     }
-    // {
-    //     /*------------------//
-    //     \\Tokio handler loop\\
-    //     //------------------*/
-    //     debug!("Starting thread {}", thr_id);
-    //     let handle =
-    // }
 
     for handle in handles {
         match handle.0.await {
@@ -359,6 +396,29 @@ async fn main() -> Result<()> {
             }
         };
     }
+    let _ = stop_accepting.send(());
+    let _ = accept.await;
+    signals.abort();
+    pausing.abort();
+    ensure_no_file(&(args.tmp_dir.clone() + "/master.sock"))?;
+
+    if *shared.stop.borrow() != Stop::Running {
+        let pending = queue::pending_count(&mut shared.connection.lock().unwrap())?;
+        info!("Stopped early, {} links are left for the next run", pending);
+    }
+
+    let mut summary = shared.summary.lock().unwrap();
+    summary.started_at = started_at;
+    summary.wall_time = started.elapsed().as_secs_f64();
+    summary.log();
+    let report_path = args.report.clone().unwrap_or_else(|| {
+        Path::new(&shared.logs_dir)
+            .join(format!("run-{}.json", started_at))
+            .to_string_lossy()
+            .into_owned()
+    });
+    summary.write(&report_path)?;
+    info!("Wrote the run report to {}", report_path);
 
     Ok(())
 }
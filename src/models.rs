@@ -1,5 +1,8 @@
 use diesel::prelude::*;
 
+use crate::comms::InfoDict;
+use crate::links::VideoKey;
+
 #[derive(Queryable, Selectable, PartialEq)]
 #[diesel(table_name = crate::schema::videos)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
@@ -11,6 +14,14 @@ pub struct Video {
     pub author: Option<String>,
     pub duration: Option<i64>,
     pub description: Option<String>,
+    pub thumbnail_path: Option<String>,
+    /// Upload date as YYYYMMDD
+    pub date: Option<i64>,
+    /// Full serialized `InfoDict`
+    pub other: Option<Vec<u8>>,
+    pub channel_id: Option<i64>,
+    pub album_id: Option<i64>,
+    pub track: Option<String>,
 }
 
 #[derive(Insertable, Debug)]
@@ -22,4 +33,108 @@ pub struct NewVideo {
     pub author: Option<String>,
     pub duration: Option<i64>,
     pub description: Option<String>,
+    pub thumbnail_path: Option<String>,
+    /// Upload date as YYYYMMDD
+    pub date: Option<i64>,
+    /// Full serialized `InfoDict`
+    pub other: Option<Vec<u8>>,
+    pub channel_id: Option<i64>,
+    pub album_id: Option<i64>,
+    pub track: Option<String>,
+}
+
+impl NewVideo {
+    /// The row for a finished download, not linked to its channel and album yet
+    pub fn from_info(info: &InfoDict) -> serde_json::Result<Self> {
+        // Thumbnails are written before the download starts, so their path is already known by the time it finishes
+        let thumbnail_path = info
+            .thumbnails
+            .iter()
+            .rev()
+            .find_map(|t| t.filepath.clone());
+
+        Ok(NewVideo {
+            uid: VideoKey::new(&info.extractor_key, &info.id).to_string(),
+            link: Some(info.webpage_url.clone()),
+            title: Some(info.title.clone()),
+            author: info.artist.clone(),
+            duration: Some(info.duration.into()),
+            description: Some(info.description.clone()),
+            thumbnail_path,
+            date: info.upload_date.parse().ok(),
+            other: Some(serde_json::to_vec(info)?),
+            channel_id: None,
+            album_id: None,
+            track: info.track.clone(),
+        })
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::queue)]
+pub struct NewQueueItem {
+    pub uid: String,
+    pub link: String,
+    pub title: Option<String>,
+    pub channel: Option<String>,
+    pub position: Option<i64>,
+    pub status: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::channels)]
+pub struct NewChannel {
+    pub uid: String,
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub follower_count: Option<i64>,
+    pub verified: bool,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::albums)]
+pub struct NewAlbum {
+    pub title: String,
+    pub artist: String,
+    pub release_year: Option<i64>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::video_tags)]
+pub struct NewVideoTag {
+    pub video_id: i64,
+    pub tag_id: i64,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::categories)]
+pub struct NewCategory {
+    pub video_id: i64,
+    pub name: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::chapters)]
+pub struct NewChapter {
+    pub video_id: i64,
+    pub position: i64,
+    pub title: Option<String>,
+    pub start_time: f64,
+    pub end_time: f64,
+}
+
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::files)]
+pub struct NewFile {
+    pub video_id: i64,
+    pub path: String,
+    pub role: String,
+    pub size: Option<i64>,
+    pub container: Option<String>,
+    pub codec: Option<String>,
+    pub format_id: Option<String>,
+    pub checksum: Option<String>,
+    pub mtime: Option<i64>,
 }
@@ -0,0 +1,362 @@
+use anyhow::Result;
+use log::{debug, warn};
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use serde_json::Value;
+use std::{collections::HashMap, sync::LazyLock};
+
+use crate::links::{LinkExtractor, VideoKey};
+use crate::sources::{LinkSource, SourceEntry};
+
+// Playlist items on youtube.com (playlist page and the panel next to the player) and music.youtube.com.
+// Scoping to the playlist containers keeps recommendations and sidebars out
+const PLAYLIST_ITEM_SELECTOR: &str =
+    "ytd-playlist-video-renderer, ytd-playlist-panel-video-renderer, ytmusic-playlist-shelf-renderer ytmusic-responsive-list-item-renderer";
+const JSON_PLAYLIST_CONTAINERS: [&str; 3] = [
+    "playlistVideoListRenderer",
+    "playlistPanelRenderer",
+    "musicPlaylistShelfRenderer",
+];
+
+static YTM_INITIAL_DATA: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"data:\s*'((?:[^'\\]|\\.)*)'").unwrap());
+static INDEX_PARAM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[?&;]index=(\d+)").unwrap());
+
+/// Saved YouTube/YouTube Music playlist page, read from both the rendered DOM and the embedded `ytInitialData`
+pub struct PageSource(pub LinkExtractor);
+
+impl LinkSource for PageSource {
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>> {
+        let document = Html::parse_document(content);
+
+        // The DOM has everything the browser had scrolled to, the JSON only has the first page of items but is
+        // there even when the page was saved as HTML only, so the DOM goes first and the JSON fills the gaps
+        let dom_entries = dom_entries(&document);
+        let json_entries = json_entries(&document);
+        debug!(
+            "Found {} playlist items in the DOM, {} in the embedded JSON",
+            dom_entries.len(),
+            json_entries.len()
+        );
+
+        let entries = merge_entries(dom_entries.into_iter().chain(json_entries));
+        if entries.is_empty() {
+            warn!("No playlist items found in the page, falling back to the link regex");
+            return Ok(self
+                .0
+                .extract(content)
+                .into_iter()
+                .map(SourceEntry::from)
+                .collect());
+        }
+        Ok(entries)
+    }
+}
+
+fn selector(s: &str) -> Selector {
+    Selector::parse(s).unwrap()
+}
+
+fn first_text(el: &ElementRef, sel: &str) -> Option<String> {
+    el.select(&selector(sel))
+        .map(|e| e.text().collect::<String>().trim().to_owned())
+        .find(|t| !t.is_empty())
+}
+
+fn dom_entries(document: &Html) -> Vec<SourceEntry> {
+    let link_sel = selector("a#video-title, a#wc-endpoint, a[href*=\"watch?v=\"]");
+
+    document
+        .select(&selector(PLAYLIST_ITEM_SELECTOR))
+        .filter_map(|item| {
+            let anchor = item.select(&link_sel).next()?;
+            let link = anchor.value().attr("href")?.to_owned();
+
+            let title = anchor
+                .value()
+                .attr("title")
+                .map(str::to_owned)
+                .or_else(|| first_text(&item, "#video-title, .title"));
+            let channel = first_text(
+                &item,
+                "ytd-channel-name a, #byline, .secondary-flex-columns yt-formatted-string",
+            );
+            let position = first_text(&item, "#index")
+                .and_then(|i| i.parse().ok())
+                .or_else(|| INDEX_PARAM.captures(&link).and_then(|c| c[1].parse().ok()));
+
+            Some(SourceEntry {
+                link,
+                title,
+                channel,
+                position,
+            })
+        })
+        .collect()
+}
+
+fn json_entries(document: &Html) -> Vec<SourceEntry> {
+    let mut entries = Vec::new();
+    for script in document.select(&selector("script")) {
+        let text = script.text().collect::<String>();
+        if let Some(start) = text.find("ytInitialData") {
+            if let Some(data) = parse_leading_json(&text[start..]) {
+                collect_json_entries(&data, false, &mut entries);
+            }
+        } else if text.contains("initialData.push") {
+            // YouTube Music ships its data as JS string literals with \x escapes
+            for caps in YTM_INITIAL_DATA.captures_iter(&text) {
+                if let Some(data) = parse_leading_json(&unescape_js(&caps[1])) {
+                    collect_json_entries(&data, false, &mut entries);
+                }
+            }
+        }
+    }
+    entries
+}
+
+/// Parses the first JSON object in `s`, ignoring whatever comes after it
+fn parse_leading_json(s: &str) -> Option<Value> {
+    let start = s.find('{')?;
+    serde_json::Deserializer::from_str(&s[start..])
+        .into_iter::<Value>()
+        .next()?
+        .inspect_err(|e| debug!("Unable to parse embedded page data: {}", e))
+        .ok()
+}
+
+fn unescape_js(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                out.extend(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32));
+            }
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                out.extend(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32));
+            }
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// `{"simpleText": ...}` or `{"runs": [{"text": ...}, ...]}`
+fn json_text(v: Option<&Value>) -> Option<String> {
+    let v = v?;
+    if let Some(text) = v.get("simpleText").and_then(Value::as_str) {
+        return Some(text.to_owned());
+    }
+    let text: String = v
+        .get("runs")?
+        .as_array()?
+        .iter()
+        .filter_map(|r| r.get("text").and_then(Value::as_str))
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
+
+fn json_entry(key: &str, renderer: &Value) -> Option<SourceEntry> {
+    let (id, title, channel, position) = match key {
+        "playlistVideoRenderer" | "playlistPanelVideoRenderer" => (
+            renderer.get("videoId")?,
+            json_text(renderer.get("title")),
+            json_text(renderer.get("shortBylineText")),
+            json_text(renderer.get("index").or(renderer.get("indexText"))),
+        ),
+        "musicResponsiveListItemRenderer" => (
+            renderer.pointer("/playlistItemData/videoId")?,
+            json_text(renderer.pointer("/flexColumns/0/musicResponsiveListItemFlexColumnRenderer/text")),
+            json_text(renderer.pointer("/flexColumns/1/musicResponsiveListItemFlexColumnRenderer/text")),
+            json_text(renderer.get("index")),
+        ),
+        _ => return None,
+    };
+
+    Some(SourceEntry {
+        link: VideoKey::youtube(id.as_str()?).url()?,
+        title,
+        channel,
+        position: position.and_then(|p| p.parse().ok()),
+    })
+}
+
+fn collect_json_entries(v: &Value, in_playlist: bool, out: &mut Vec<SourceEntry>) {
+    match v {
+        Value::Object(map) => {
+            for (key, inner) in map {
+                if in_playlist {
+                    if let Some(entry) = json_entry(key, inner) {
+                        out.push(entry);
+                        continue;
+                    }
+                }
+                collect_json_entries(
+                    inner,
+                    in_playlist || JSON_PLAYLIST_CONTAINERS.contains(&key.as_str()),
+                    out,
+                );
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_json_entries(item, in_playlist, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Deduplicates by video, filling gaps in earlier entries from later ones, and orders by playlist position
+fn merge_entries(entries: impl Iterator<Item = SourceEntry>) -> Vec<SourceEntry> {
+    let mut merged: Vec<SourceEntry> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    for entry in entries {
+        let key = VideoKey::normalize(&entry.link)
+            .map(|k| k.to_string())
+            .unwrap_or_else(|| entry.link.clone());
+        match index.get(&key) {
+            Some(&i) => {
+                let existing = &mut merged[i];
+                existing.title = existing.title.take().or(entry.title);
+                existing.channel = existing.channel.take().or(entry.channel);
+                existing.position = existing.position.or(entry.position);
+            }
+            None => {
+                index.insert(key, merged.len());
+                merged.push(entry);
+            }
+        }
+    }
+
+    // Stable, so entries without a position keep their document order after the numbered ones
+    merged.sort_by_key(|e| e.position.unwrap_or(usize::MAX));
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(page: &str) -> Vec<SourceEntry> {
+        PageSource(LinkExtractor::new(r"watch\?v=(?P<id>[\w-]{11})").unwrap())
+            .links(page)
+            .unwrap()
+    }
+
+    fn entry(link: &str, title: &str, channel: &str, position: usize) -> SourceEntry {
+        SourceEntry {
+            link: link.to_owned(),
+            title: Some(title.to_owned()),
+            channel: Some(channel.to_owned()),
+            position: Some(position),
+        }
+    }
+
+    #[test]
+    fn youtube_playlist_dom() {
+        let page = r#"<html><body>
+<ytd-playlist-video-renderer>
+  <div id="index">2</div>
+  <a id="video-title" href="/watch?v=dQw4w9WgXcQ&amp;list=PL1&amp;index=2" title="Never Gonna Give You Up"></a>
+  <ytd-channel-name><a href="/@rick">Rick Astley</a></ytd-channel-name>
+</ytd-playlist-video-renderer>
+<ytd-playlist-video-renderer>
+  <div id="index">1</div>
+  <a id="video-title" href="/watch?v=Xq1Hm3t7bKc&amp;list=PL1&amp;index=1" title="Harbour Lights"></a>
+  <ytd-channel-name><a href="/@harbour">Harbour</a></ytd-channel-name>
+</ytd-playlist-video-renderer>
+<ytd-compact-video-renderer><a href="/watch?v=aaaaaaaaaaa">Recommended</a></ytd-compact-video-renderer>
+</body></html>"#;
+        assert_eq!(
+            entries(page),
+            [
+                entry("/watch?v=Xq1Hm3t7bKc&list=PL1&index=1", "Harbour Lights", "Harbour", 1),
+                entry("/watch?v=dQw4w9WgXcQ&list=PL1&index=2", "Never Gonna Give You Up", "Rick Astley", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn youtube_initial_data() {
+        // Saved as HTML only: nothing rendered, the items are in the embedded JSON. The renderer outside the playlist
+        // is a recommendation
+        let page = r#"<html><body><script>var ytInitialData = {"contents": {"playlistVideoListRenderer": {"contents": [
+  {"playlistVideoRenderer": {"videoId": "Xq1Hm3t7bKc", "title": {"runs": [{"text": "Harbour "}, {"text": "Lights"}]},
+   "shortBylineText": {"runs": [{"text": "Harbour"}]}, "index": {"simpleText": "1"}}},
+  {"playlistVideoRenderer": {"videoId": "dQw4w9WgXcQ", "title": {"simpleText": "Never Gonna Give You Up"},
+   "shortBylineText": {"simpleText": "Rick Astley"}, "index": {"simpleText": "2"}}}
+]}, "secondary": {"playlistVideoRenderer": {"videoId": "aaaaaaaaaaa"}}}};</script></body></html>"#;
+        assert_eq!(
+            entries(page),
+            [
+                entry("https://www.youtube.com/watch?v=Xq1Hm3t7bKc", "Harbour Lights", "Harbour", 1),
+                entry("https://www.youtube.com/watch?v=dQw4w9WgXcQ", "Never Gonna Give You Up", "Rick Astley", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn youtube_music_initial_data() {
+        let data = r#"{"musicPlaylistShelfRenderer": {"contents": [{"musicResponsiveListItemRenderer": {
+  "playlistItemData": {"videoId": "Xq1Hm3t7bKc"}, "index": {"simpleText": "1"},
+  "flexColumns": [
+    {"musicResponsiveListItemFlexColumnRenderer": {"text": {"runs": [{"text": "Harbour Lights"}]}}},
+    {"musicResponsiveListItemFlexColumnRenderer": {"text": {"runs": [{"text": "Harbour"}]}}}
+  ]}}]}}"#;
+        // Shipped as a JS string literal with everything but word characters \x-escaped
+        let escaped: String = data
+            .chars()
+            .map(|c| match c {
+                c if c.is_ascii_alphanumeric() || c == ' ' => c.to_string(),
+                c => format!("\\x{:02x}", c as u32),
+            })
+            .collect();
+        let page = format!(
+            "<html><body><script>initialData.push({{path: '\\/browse', data: '{}'}});</script></body></html>",
+            escaped
+        );
+        assert_eq!(
+            entries(&page),
+            [entry("https://www.youtube.com/watch?v=Xq1Hm3t7bKc", "Harbour Lights", "Harbour", 1)]
+        );
+    }
+
+    #[test]
+    fn dom_and_json_merge() {
+        // The DOM only has what was scrolled to and lacks the channel, the JSON fills in the rest
+        let page = r#"<html><body>
+<ytd-playlist-video-renderer>
+  <a id="video-title" href="/watch?v=dQw4w9WgXcQ&amp;index=2" title="Never Gonna Give You Up"></a>
+</ytd-playlist-video-renderer>
+<script>var ytInitialData = {"playlistVideoListRenderer": {"contents": [
+  {"playlistVideoRenderer": {"videoId": "Xq1Hm3t7bKc", "title": {"simpleText": "Harbour Lights"},
+   "shortBylineText": {"simpleText": "Harbour"}, "index": {"simpleText": "1"}}},
+  {"playlistVideoRenderer": {"videoId": "dQw4w9WgXcQ", "title": {"simpleText": "Rickroll"},
+   "shortBylineText": {"simpleText": "Rick Astley"}, "index": {"simpleText": "2"}}}
+]}};</script></body></html>"#;
+        assert_eq!(
+            entries(page),
+            [
+                entry("https://www.youtube.com/watch?v=Xq1Hm3t7bKc", "Harbour Lights", "Harbour", 1),
+                entry("/watch?v=dQw4w9WgXcQ&index=2", "Never Gonna Give You Up", "Rick Astley", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_regex() {
+        let page = r#"<html><body><a href="/watch?v=Xq1Hm3t7bKc">x</a></body></html>"#;
+        assert_eq!(entries(page), [SourceEntry::from("Xq1Hm3t7bKc".to_owned())]);
+    }
+}
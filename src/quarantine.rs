@@ -0,0 +1,291 @@
+use anyhow::{bail, Context, Result};
+use diesel::sqlite::SqliteConnection;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::comms::{InfoDict, MovedFiles};
+use crate::library;
+use crate::links::VideoKey;
+use crate::queue;
+
+/// Bytes of the payload kept on either side of where parsing stopped
+const CONTEXT_BYTES: usize = 40;
+/// Where replayed payloads are moved to, inside the quarantine dir
+const REPLAYED_DIR: &str = "replayed";
+
+/// Which worker message a payload came in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    VideoInfo,
+    FilesMoved,
+}
+
+/// A worker payload the master couldn't use, saved with what went wrong so it can be looked at, turned into a test
+/// fixture or replayed once the models catch up
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Quarantined {
+    pub kind: PayloadKind,
+    pub thr_id: usize,
+    /// Unix milliseconds
+    pub saved_at: u128,
+    pub error: String,
+    /// Payload around where parsing stopped, if it stopped somewhere in particular
+    pub context: Option<String>,
+    /// Character of `context` parsing stopped at
+    pub context_at: Option<usize>,
+    /// Verbatim, it may not even be valid JSON
+    pub payload: String,
+}
+
+impl Quarantined {
+    pub fn new(kind: PayloadKind, thr_id: usize, payload: &str, error: String) -> Self {
+        Quarantined {
+            kind,
+            thr_id,
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            error,
+            context: None,
+            context_at: None,
+            payload: payload.to_owned(),
+        }
+    }
+
+    pub fn from_parse_error(kind: PayloadKind, thr_id: usize, payload: &str, e: &serde_json::Error) -> Self {
+        let (context, context_at) = context(payload, e.line(), e.column());
+        Quarantined {
+            context: Some(context),
+            context_at: Some(context_at),
+            ..Quarantined::new(kind, thr_id, payload, e.to_string())
+        }
+    }
+
+    /// Logs what went wrong, pointing at the spot in the payload if there is one
+    pub fn log(&self, path: &Path) {
+        error!(
+            "Unable to use {:?} from thread {}: {}, saved to {}",
+            self.kind,
+            self.thr_id,
+            self.error,
+            path.display()
+        );
+        if let (Some(context), Some(at)) = (&self.context, self.context_at) {
+            error!("  {}", context);
+            error!("  {}^", " ".repeat(at));
+        }
+    }
+
+    /// Writes it to its own file in `dir`, which is created if needed. Payloads saved within the same millisecond
+    /// get a counter after their time rather than overwriting each other
+    pub fn save(&self, dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+        let json = serde_json::to_vec_pretty(self)?;
+        for n in 0.. {
+            let suffix = if n == 0 { String::new() } else { format!(".{}", n) };
+            let path = dir.join(format!(
+                "{}{}-thr{}-{:?}.json",
+                self.saved_at, suffix, self.thr_id, self.kind
+            ));
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).with_context(|| format!("Unable to create {}", path.display())),
+            };
+            file.write_all(&json)
+                .with_context(|| format!("Unable to write {}", path.display()))?;
+            return Ok(path);
+        }
+        unreachable!("ran out of quarantine file names")
+    }
+}
+
+/// Where bad payloads go, inside the logs dir
+pub fn dir(logs_dir: &str) -> PathBuf {
+    Path::new(logs_dir).join("quarantine")
+}
+
+/// Cuts out up to `CONTEXT_BYTES` either side of `line`:`column` (1-based, as serde_json reports them) on char
+/// boundaries, returning it with newlines flattened and the position of the spot in chars
+fn context(payload: &str, line: usize, column: usize) -> (String, usize) {
+    let line_start: usize = payload
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let mut at = (line_start + column.saturating_sub(1)).min(payload.len());
+    while !payload.is_char_boundary(at) {
+        at -= 1;
+    }
+    let mut start = at.saturating_sub(CONTEXT_BYTES);
+    while !payload.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (at + CONTEXT_BYTES).min(payload.len());
+    while !payload.is_char_boundary(end) {
+        end += 1;
+    }
+    let flatten = |s: &str| s.replace(['\n', '\r', '\t'], " ");
+    (
+        flatten(&payload[start..end]),
+        payload[start..at].chars().count(),
+    )
+}
+
+/// Parses every payload in the quarantine dir against the current models and records the ones that now work,
+/// moving them out of the way. Info dicts go first, so their videos exist by the time their files are recorded
+pub fn replay(conn: &mut SqliteConnection, dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        info!("Nothing quarantined in {}", dir.display());
+        return Ok(());
+    }
+    let mut saved = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "json") {
+            let quarantined: Quarantined = match fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|b| Ok(serde_json::from_slice(&b)?))
+            {
+                Ok(q) => q,
+                Err(e) => {
+                    warn!("Skipping {}: {:#}", path.display(), e);
+                    continue;
+                }
+            };
+            saved.push((path, quarantined));
+        }
+    }
+    saved.sort_by_key(|(_, q)| (q.kind != PayloadKind::VideoInfo, q.saved_at));
+    if saved.is_empty() {
+        info!("Nothing quarantined in {}", dir.display());
+        return Ok(());
+    }
+
+    let replayed_dir = dir.join(REPLAYED_DIR);
+    let (mut replayed, mut failing) = (0, 0);
+    for (path, quarantined) in saved {
+        match ingest(conn, &quarantined) {
+            Ok(what) => {
+                info!("{}: {}", path.display(), what);
+                fs::create_dir_all(&replayed_dir)?;
+                fs::rename(&path, replayed_dir.join(path.file_name().unwrap()))
+                    .with_context(|| format!("Unable to move {} out of the quarantine", path.display()))?;
+                replayed += 1;
+            }
+            Err(e) => {
+                warn!("{} still fails: {:#}", path.display(), e);
+                failing += 1;
+            }
+        }
+    }
+    info!("Replayed {} payloads, {} still fail", replayed, failing);
+    Ok(())
+}
+
+/// Records a quarantined payload the way the master would have, describing what it did
+fn ingest(conn: &mut SqliteConnection, quarantined: &Quarantined) -> Result<String> {
+    match quarantined.kind {
+        PayloadKind::VideoInfo => {
+            let info: InfoDict = serde_json::from_str(&quarantined.payload)?;
+            // The info is sent before the download starts, which may have failed or been cancelled since
+            let key = VideoKey::new(&info.extractor_key, &info.id).to_string();
+            if !queue::is_quarantined(conn, &key, &info.original_url)? {
+                bail!("the queue has no finished download of {} waiting for it", info.webpage_url);
+            }
+            let recorded = library::record_video(conn, &info)?;
+            queue::finish_quarantined(conn, &key, &info.original_url)?;
+            Ok(match recorded {
+                true => format!("recorded {}", info.webpage_url),
+                false => format!("{} was already recorded", info.webpage_url),
+            })
+        }
+        PayloadKind::FilesMoved => {
+            let moved: MovedFiles = serde_json::from_str(&quarantined.payload)?;
+            // The stream details came with progress ticks that are long gone, so only what can be told apart by
+            // extension (subtitles) gets recorded
+            let recorded = library::record_files(conn, &moved, None, None)?;
+            Ok(format!(
+                "recorded {} files for {}",
+                recorded, moved.filepath
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::NewQueueItem;
+    use crate::queue::QueueStatus;
+    use crate::schema::{queue as q, videos};
+    use diesel::prelude::*;
+    use diesel_migrations::MigrationHarness;
+
+    /// A library with `link` queued as `status`, and a quarantined info dict of it
+    fn library(status: QueueStatus) -> (SqliteConnection, Quarantined) {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        conn.run_pending_migrations(crate::EMBEDDED_MIGRATIONS).unwrap();
+        let link = "https://www.youtube.com/watch?v=Xq1Hm3t7bKc";
+        diesel::insert_into(q::table)
+            .values(NewQueueItem {
+                uid: "youtube Xq1Hm3t7bKc".to_owned(),
+                link: link.to_owned(),
+                title: None,
+                channel: None,
+                position: None,
+                status: status.as_str().to_owned(),
+                created_at: 0,
+                updated_at: 0,
+            })
+            .execute(&mut conn)
+            .unwrap();
+        let info = InfoDict {
+            id: "Xq1Hm3t7bKc".to_owned(),
+            extractor_key: "Youtube".to_owned(),
+            webpage_url: link.to_owned(),
+            original_url: link.to_owned(),
+            ..Default::default()
+        };
+        let payload = serde_json::to_string(&info).unwrap();
+        (conn, Quarantined::new(PayloadKind::VideoInfo, 0, &payload, "test".to_owned()))
+    }
+
+    #[test]
+    fn replays_finished_downloads_only() {
+        let (mut conn, quarantined) = library(QueueStatus::Failed);
+        assert!(ingest(&mut conn, &quarantined).is_err());
+        assert_eq!(videos::table.count().get_result::<i64>(&mut conn).unwrap(), 0);
+
+        let (mut conn, quarantined) = library(QueueStatus::Quarantined);
+        ingest(&mut conn, &quarantined).unwrap();
+        assert_eq!(videos::table.count().get_result::<i64>(&mut conn).unwrap(), 1);
+        let status: String = q::table.select(q::status).first(&mut conn).unwrap();
+        assert_eq!(status, QueueStatus::Done.as_str());
+    }
+
+    #[test]
+    fn same_millisecond_saves_dont_collide() {
+        let dir = std::env::temp_dir().join(format!("rhytm-quarantine-{}", std::process::id()));
+        let first = Quarantined::new(PayloadKind::VideoInfo, 1, "{", "first".to_owned());
+        let second = Quarantined {
+            saved_at: first.saved_at,
+            ..Quarantined::new(PayloadKind::VideoInfo, 1, "{", "second".to_owned())
+        };
+
+        let paths = [first.save(&dir).unwrap(), second.save(&dir).unwrap()];
+        assert_ne!(paths[0], paths[1]);
+        for (path, error) in paths.iter().zip(["first", "second"]) {
+            let saved: Quarantined = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+            assert_eq!(saved.error, error);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
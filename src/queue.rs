@@ -0,0 +1,396 @@
+use anyhow::{Context, Result};
+use diesel::{prelude::*, sqlite::SqliteConnection};
+use log::debug;
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::links::{self, VideoKey};
+use crate::models::NewQueueItem;
+use crate::retry::Verdict;
+use crate::schema::queue::dsl as q;
+use crate::sources::SourceEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueStatus {
+    Pending,
+    InProgress,
+    Done,
+    Failed,
+    Skipped,
+    /// Failed for good, see `retry::is_permanent`. Left alone until a recheck
+    Abandoned,
+    /// Downloaded, but its info couldn't be read and waits in the quarantine to be replayed
+    Quarantined,
+}
+
+impl QueueStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueStatus::Pending => "pending",
+            QueueStatus::InProgress => "in_progress",
+            QueueStatus::Done => "done",
+            QueueStatus::Failed => "failed",
+            QueueStatus::Skipped => "skipped",
+            QueueStatus::Abandoned => "abandoned",
+            QueueStatus::Quarantined => "quarantined",
+        }
+    }
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Front for the `queue` table, which is what workers get their batches from so an interrupted run picks up where it stopped
+pub struct LinkQueue {
+    downloaded: HashSet<VideoKey>,
+    /// Links offered to the queue, duplicates included
+    pub found: usize,
+    /// Links dropped because they are already in the DB
+    pub in_db: usize,
+    /// Links dropped because they failed permanently before
+    pub abandoned: usize,
+    /// Links added to the queue or put back into it during this run
+    pub queued: usize,
+}
+
+impl LinkQueue {
+    pub fn new(downloaded: HashSet<VideoKey>) -> Self {
+        LinkQueue {
+            downloaded,
+            found: 0,
+            in_db: 0,
+            abandoned: 0,
+            queued: 0,
+        }
+    }
+
+    /// Queues `entry` unless it was downloaded or queued before, returns whether it was queued
+    pub fn push(&mut self, conn: &mut SqliteConnection, entry: SourceEntry) -> Result<bool> {
+        self.found += 1;
+        let l = entry.link;
+        // Links we can't make sense of are passed to yt-dlp as-is and only deduplicated verbatim
+        let (key, url) = match VideoKey::normalize(&l) {
+            Some(key) => {
+                if self.downloaded.contains(&key) {
+                    self.in_db += 1;
+                    return Ok(false);
+                }
+                let url = key.url().unwrap_or_else(|| l.clone());
+                (key.to_string(), url)
+            }
+            None if links::is_collection(&l) => {
+                debug!("{:?} is a playlist or channel, a worker will expand it", l);
+                (l.clone(), l)
+            }
+            None => {
+                debug!("Unable to normalize link {:?}, passing it as-is", l);
+                (l.clone(), l)
+            }
+        };
+
+        let existing: Option<String> = q::queue
+            .filter(q::uid.eq(&key))
+            .select(q::status)
+            .first(conn)
+            .optional()
+            .context("Unable to look up queue item")?;
+
+        let now = unix_now();
+        match existing.as_deref() {
+            None => {
+                let item = NewQueueItem {
+                    uid: key,
+                    link: url.clone(),
+                    title: entry.title.clone(),
+                    channel: entry.channel.clone(),
+                    position: entry.position.map(|p| p as i64),
+                    status: QueueStatus::Pending.as_str().to_owned(),
+                    created_at: now,
+                    updated_at: now,
+                };
+                diesel::insert_into(q::queue)
+                    .values(item)
+                    .execute(conn)
+                    .context("Unable to insert queue item")?;
+            }
+            // Failed links get another go with fresh attempts, playlists and channels get re-expanded to pick up new videos
+            Some(s) if s == QueueStatus::Failed.as_str() || (s == QueueStatus::Done.as_str() && links::is_collection(&url)) => {
+                diesel::update(q::queue.filter(q::uid.eq(&key)))
+                    .set((
+                        q::status.eq(QueueStatus::Pending.as_str()),
+                        q::attempts.eq(0),
+                        q::retry_at.eq(None::<i64>),
+                        q::updated_at.eq(now),
+                    ))
+                    .execute(conn)
+                    .context("Unable to requeue item")?;
+            }
+            Some(s) if s == QueueStatus::Abandoned.as_str() => {
+                debug!("{} failed permanently before, skipping it", url);
+                self.abandoned += 1;
+                return Ok(false);
+            }
+            Some(s) => {
+                debug!("{} is already in the queue as {}", url, s);
+                return Ok(false);
+            }
+        }
+
+        debug!(
+            "Queued #{} {} - {} ({})",
+            entry.position.unwrap_or(self.found),
+            entry.channel.as_deref().unwrap_or("?"),
+            entry.title.as_deref().unwrap_or("?"),
+            url
+        );
+        self.queued += 1;
+        Ok(true)
+    }
+}
+
+/// Puts items a previous run left in progress back in line, returns how many there were
+pub fn reset_interrupted(conn: &mut SqliteConnection) -> Result<usize> {
+    diesel::update(q::queue.filter(q::status.eq(QueueStatus::InProgress.as_str())))
+        .set((
+            q::status.eq(QueueStatus::Pending.as_str()),
+            q::updated_at.eq(unix_now()),
+        ))
+        .execute(conn)
+        .context("Unable to reset interrupted queue items")
+}
+
+pub fn pending_count(conn: &mut SqliteConnection) -> Result<i64> {
+    q::queue
+        .filter(q::status.eq(QueueStatus::Pending.as_str()))
+        .count()
+        .get_result(conn)
+        .context("Unable to count pending queue items")
+}
+
+/// Puts links that failed permanently back in line, returns how many there were
+pub fn recheck(conn: &mut SqliteConnection) -> Result<usize> {
+    diesel::update(q::queue.filter(q::status.eq(QueueStatus::Abandoned.as_str())))
+        .set((
+            q::status.eq(QueueStatus::Pending.as_str()),
+            q::attempts.eq(0),
+            q::retry_at.eq(None::<i64>),
+            q::updated_at.eq(unix_now()),
+        ))
+        .execute(conn)
+        .context("Unable to requeue abandoned queue items")
+}
+
+/// When the earliest pending link waiting out a retry delay is due, if any is
+pub fn next_retry_at(conn: &mut SqliteConnection) -> Result<Option<i64>> {
+    q::queue
+        .filter(q::status.eq(QueueStatus::Pending.as_str()))
+        .select(diesel::dsl::min(q::retry_at))
+        .first(conn)
+        .context("Unable to look up queued retries")
+}
+
+/// Hands up to `size` pending links that aren't waiting out a retry delay to `worker`, oldest first
+pub fn next_batch(conn: &mut SqliteConnection, size: usize, worker: usize) -> Result<Option<Vec<String>>> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let items: Vec<(i64, String)> = q::queue
+            .filter(q::status.eq(QueueStatus::Pending.as_str()))
+            .filter(q::retry_at.is_null().or(q::retry_at.le(unix_now())))
+            .order(q::id.asc())
+            .limit(size as i64)
+            .select((q::id, q::link))
+            .load(conn)?;
+
+        if items.is_empty() {
+            return Ok(None);
+        }
+
+        let (ids, batch): (Vec<i64>, Vec<String>) = items.into_iter().unzip();
+        diesel::update(q::queue.filter(q::id.eq_any(ids)))
+            .set((
+                q::status.eq(QueueStatus::InProgress.as_str()),
+                q::attempts.eq(q::attempts + 1),
+                q::worker_id.eq(Some(worker as i64)),
+                q::updated_at.eq(unix_now()),
+            ))
+            .execute(conn)?;
+        Ok(Some(batch))
+    })
+    .context("Unable to take a batch from the queue")
+}
+
+/// Records the outcome for the in-progress item matching either the canonical key or the link handed to the worker
+pub fn finish(conn: &mut SqliteConnection, key: &str, link: &str, status: QueueStatus, error: Option<&str>) -> Result<usize> {
+    diesel::update(
+        q::queue
+            .filter(q::uid.eq(key).or(q::link.eq(link)))
+            .filter(q::status.eq(QueueStatus::InProgress.as_str())),
+    )
+    .set((
+        q::status.eq(status.as_str()),
+        q::last_error.eq(error),
+        q::error_kind.eq(None::<String>),
+        q::retry_at.eq(None::<i64>),
+        q::updated_at.eq(unix_now()),
+    ))
+    .execute(conn)
+    .context("Unable to update queue item")
+}
+
+/// Whether the item matching either the canonical key or the link it was handed out as was downloaded with its info
+/// quarantined
+pub fn is_quarantined(conn: &mut SqliteConnection, key: &str, link: &str) -> Result<bool> {
+    diesel::select(diesel::dsl::exists(
+        q::queue
+            .filter(q::uid.eq(key).or(q::link.eq(link)))
+            .filter(q::status.eq(QueueStatus::Quarantined.as_str())),
+    ))
+    .get_result(conn)
+    .context("Unable to look up queue item")
+}
+
+/// Marks the quarantined item matching either the canonical key or the link it was handed out as done, once its info
+/// has been replayed
+pub fn finish_quarantined(conn: &mut SqliteConnection, key: &str, link: &str) -> Result<usize> {
+    diesel::update(
+        q::queue
+            .filter(q::uid.eq(key).or(q::link.eq(link)))
+            .filter(q::status.eq(QueueStatus::Quarantined.as_str())),
+    )
+    .set((
+        q::status.eq(QueueStatus::Done.as_str()),
+        q::last_error.eq(None::<String>),
+        q::updated_at.eq(unix_now()),
+    ))
+    .execute(conn)
+    .context("Unable to update queue item")
+}
+
+/// How many times the item `worker` was handed as `link` has been tried
+pub fn attempts(conn: &mut SqliteConnection, key: &str, link: &str, worker: usize) -> Result<u32> {
+    let attempts: Option<i64> = q::queue
+        .filter(q::uid.eq(key).or(q::link.eq(link)))
+        .filter(q::worker_id.eq(worker as i64))
+        .select(q::attempts)
+        .first(conn)
+        .optional()
+        .context("Unable to look up queue item attempts")?;
+    Ok(attempts.unwrap_or(1) as u32)
+}
+
+/// Records the error yt-dlp raised for the item `worker` was handed as `link`, and either puts it back in line or
+/// gives up on it as `verdict` says. Also overrides an outcome recorded from progress ticks, since yt-dlp can still
+/// fail after the download itself finished (e.g. merging)
+pub fn fail(conn: &mut SqliteConnection, key: &str, link: &str, worker: usize, verdict: Verdict, kind: &str, error: &str) -> Result<usize> {
+    let finished = [
+        QueueStatus::InProgress.as_str(),
+        QueueStatus::Done.as_str(),
+        QueueStatus::Skipped.as_str(),
+    ];
+    let (status, retry_at) = match verdict {
+        Verdict::Retry { retry_at } => (QueueStatus::Pending, Some(retry_at)),
+        Verdict::GiveUp => (QueueStatus::Failed, None),
+        Verdict::Permanent => (QueueStatus::Abandoned, None),
+    };
+    diesel::update(
+        q::queue
+            .filter(q::uid.eq(key).or(q::link.eq(link)))
+            .filter(q::worker_id.eq(worker as i64))
+            .filter(q::status.eq_any(finished)),
+    )
+    .set((
+        q::status.eq(status.as_str()),
+        q::last_error.eq(error),
+        q::error_kind.eq(kind),
+        q::retry_at.eq(retry_at),
+        q::updated_at.eq(unix_now()),
+    ))
+    .execute(conn)
+    .context("Unable to fail queue item")
+}
+
+/// Puts whatever `worker` still holds from `batch` back in line, for when the worker was stopped mid-batch
+pub fn requeue_unfinished(conn: &mut SqliteConnection, batch: &[String], worker: usize) -> Result<usize> {
+    diesel::update(
+        q::queue
+            .filter(q::link.eq_any(batch))
+            .filter(q::worker_id.eq(worker as i64))
+            .filter(q::status.eq(QueueStatus::InProgress.as_str())),
+    )
+    .set((
+        q::status.eq(QueueStatus::Pending.as_str()),
+        q::updated_at.eq(unix_now()),
+    ))
+    .execute(conn)
+    .context("Unable to requeue unfinished queue items")
+}
+
+/// Fails the link `worker` was on when it died or hung as `verdict` says for it and how often it was tried, so a link
+/// that keeps taking its worker down ends up failed like any other. Links are worked through in batch order, so that's
+/// the first one still in progress. The ones after it never started and go back in line without the attempt they were
+/// charged. Returns the failed link with its attempts and verdict, if the worker held any
+pub fn fail_lost(
+    conn: &mut SqliteConnection,
+    batch: &[String],
+    worker: usize,
+    verdict: impl FnOnce(&str, u32) -> Verdict,
+    kind: &str,
+    error: &str,
+) -> Result<Option<(String, u32, Verdict)>> {
+    let held: Vec<(String, String, i64)> = q::queue
+        .filter(q::link.eq_any(batch))
+        .filter(q::worker_id.eq(worker as i64))
+        .filter(q::status.eq(QueueStatus::InProgress.as_str()))
+        .select((q::uid, q::link, q::attempts))
+        .load(conn)
+        .context("Unable to look up lost queue items")?;
+    let Some((key, link, attempts)) = batch
+        .iter()
+        .find_map(|l| held.iter().find(|(_, link, _)| link == l))
+        .cloned()
+    else {
+        return Ok(None);
+    };
+
+    let untouched: Vec<&String> = held.iter().map(|(_, l, _)| l).filter(|l| **l != link).collect();
+    diesel::update(
+        q::queue
+            .filter(q::link.eq_any(untouched))
+            .filter(q::worker_id.eq(worker as i64))
+            .filter(q::status.eq(QueueStatus::InProgress.as_str())),
+    )
+    .set((
+        q::status.eq(QueueStatus::Pending.as_str()),
+        q::attempts.eq(q::attempts - 1),
+        q::updated_at.eq(unix_now()),
+    ))
+    .execute(conn)
+    .context("Unable to requeue unfinished queue items")?;
+
+    let attempts = attempts as u32;
+    let verdict = verdict(&link, attempts);
+    fail(conn, &key, &link, worker, verdict, kind, error)?;
+    Ok(Some((link, attempts, verdict)))
+}
+
+/// Fails whatever `worker` still holds from `batch`, i.e. links yt-dlp never reported a finished download for
+pub fn fail_unfinished(conn: &mut SqliteConnection, batch: &[String], worker: usize) -> Result<usize> {
+    diesel::update(
+        q::queue
+            .filter(q::link.eq_any(batch))
+            .filter(q::worker_id.eq(worker as i64))
+            .filter(q::status.eq(QueueStatus::InProgress.as_str())),
+    )
+    .set((
+        q::status.eq(QueueStatus::Failed.as_str()),
+        q::last_error.eq("yt-dlp did not report a finished download"),
+        q::updated_at.eq(unix_now()),
+    ))
+    .execute(conn)
+    .context("Unable to fail unfinished queue items")
+}
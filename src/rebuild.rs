@@ -0,0 +1,344 @@
+use anyhow::{Context, Result};
+use diesel::{prelude::*, sqlite::SqliteConnection};
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::comms::{DownloadStatus, InfoDict, MovedFiles};
+use crate::library;
+use crate::links::VideoKey;
+use crate::models::{NewVideo, Video};
+use crate::schema::{channels, videos};
+
+/// A column of a video or its channel that the logs disagree with the DB on, or among themselves
+#[derive(Debug)]
+pub struct Conflict {
+    /// Of the video, or of the channel for `channels.*` fields
+    pub uid: String,
+    pub field: &'static str,
+    /// What's in the DB now
+    pub kept: String,
+    /// Every other value the logs had, newest first
+    pub ignored: Vec<String>,
+}
+
+/// What a rebuild did
+#[derive(Debug, Default)]
+pub struct Rebuilt {
+    pub logs: usize,
+    pub unreadable: usize,
+    pub inserted: usize,
+    pub repaired: usize,
+    pub files: usize,
+    /// Videos that couldn't be recorded, nothing of them is
+    pub failed: usize,
+    /// One per video or channel and column
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Reads every `DownloadStatus` the master logged when a stream finished (see `DownloadStatus::log_name`, older
+/// versions named them after the downloaded file) and records what's missing from the DB:
+/// videos with their channel, album, tags and chapters, and the files they left on disk. Videos already in the DB
+/// get their empty columns filled in and whatever channel, album, tags, categories and chapters they lack, differing
+/// values are reported and left alone. So are the columns of channels that are in the DB already
+pub fn rebuild(conn: &mut SqliteConnection, logs_dir: &Path) -> Result<Rebuilt> {
+    let mut rebuilt = Rebuilt::default();
+    let mut by_video: BTreeMap<String, Vec<DownloadStatus>> = BTreeMap::new();
+    for entry in fs::read_dir(logs_dir).with_context(|| format!("Unable to read {}", logs_dir.display()))? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let Some(status) = read_log(&path, &mut rebuilt) else {
+            continue;
+        };
+        let uid = VideoKey::new(&status.info_dict.extractor_key, &status.info_dict.id).to_string();
+        by_video.entry(uid).or_default().push(status);
+    }
+
+    for (uid, mut streams) in by_video {
+        // Newest extraction first, that's the metadata we go with
+        streams.sort_by_key(|s| std::cmp::Reverse(s.info_dict.epoch));
+        // One video failing doesn't stop the others, it's rolled back and counted
+        let counted = (rebuilt.inserted, rebuilt.repaired, rebuilt.files, rebuilt.conflicts.len());
+        if let Err(e) = conn.transaction(|conn| record(conn, &uid, &streams, &mut rebuilt)) {
+            warn!("Unable to record {}: {:#}", uid, e);
+            (rebuilt.inserted, rebuilt.repaired, rebuilt.files) = (counted.0, counted.1, counted.2);
+            rebuilt.conflicts.truncate(counted.3);
+            rebuilt.failed += 1;
+        }
+    }
+
+    info!(
+        "Read {} download logs ({} unreadable): {} videos added, {} repaired, {} failed, {} files recorded, {} conflicts",
+        rebuilt.logs,
+        rebuilt.unreadable,
+        rebuilt.inserted,
+        rebuilt.repaired,
+        rebuilt.failed,
+        rebuilt.files,
+        rebuilt.conflicts.len()
+    );
+    for c in &rebuilt.conflicts {
+        warn!(
+            "  {} {}: kept {:?}, ignored {:?}",
+            c.uid, c.field, c.kept, c.ignored
+        );
+    }
+    Ok(rebuilt)
+}
+
+/// Parses a download log, `None` for files that aren't one (run reports) or can't be read
+fn read_log(path: &Path, rebuilt: &mut Rebuilt) -> Option<DownloadStatus> {
+    let value: Value = match fs::read(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|b| Ok(serde_json::from_slice(&b)?))
+    {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("Skipping {}: {:#}", path.display(), e);
+            rebuilt.unreadable += 1;
+            return None;
+        }
+    };
+    if value.get("info_dict").is_none() {
+        debug!("{} is not a download log", path.display());
+        return None;
+    }
+    rebuilt.logs += 1;
+    match serde_json::from_value(value) {
+        Ok(status) => Some(status),
+        Err(e) => {
+            warn!("Skipping {}: {}", path.display(), e);
+            rebuilt.unreadable += 1;
+            None
+        }
+    }
+}
+
+/// The columns of a video row that come straight from its info dict, by name
+fn columns(video: &NewVideo) -> [(&'static str, Option<String>); 7] {
+    [
+        ("link", video.link.clone()),
+        ("title", video.title.clone()),
+        ("author", video.author.clone()),
+        ("duration", video.duration.map(|d| d.to_string())),
+        ("description", video.description.clone()),
+        ("date", video.date.map(|d| d.to_string())),
+        ("track", video.track.clone()),
+    ]
+}
+
+/// Reports the columns `candidates` disagree on, once per column. The first value found is the one that's kept, so
+/// they go in order of precedence: the DB row if there is one, then the logs newest first
+fn compare(uid: &str, candidates: &[NewVideo], conflicts: &mut Vec<Conflict>) {
+    let candidates: Vec<_> = candidates.iter().map(columns).collect();
+    for i in 0..candidates[0].len() {
+        let mut values = candidates.iter().filter_map(|c| c[i].1.clone());
+        let Some(kept) = values.next() else {
+            continue;
+        };
+        let mut ignored = Vec::new();
+        for value in values {
+            if value != kept && !ignored.contains(&value) {
+                ignored.push(value);
+            }
+        }
+        if !ignored.is_empty() {
+            conflicts.push(Conflict {
+                uid: uid.to_owned(),
+                field: candidates[0][i].0,
+                kept,
+                ignored,
+            });
+        }
+    }
+}
+
+fn record(conn: &mut SqliteConnection, uid: &str, streams: &[DownloadStatus], rebuilt: &mut Rebuilt) -> Result<()> {
+    let newest = &streams[0];
+    let logged = streams
+        .iter()
+        .map(|s| NewVideo::from_info(&s.info_dict))
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    let existing: Option<Video> = videos::table
+        .filter(videos::uid.eq(uid))
+        .select(Video::as_select())
+        .first(conn)
+        .optional()?;
+    match existing {
+        None => {
+            compare(uid, &logged, &mut rebuilt.conflicts);
+            library::record_video(conn, &newest.info_dict)?;
+            rebuilt.inserted += 1;
+        }
+        Some(existing) => {
+            let stored = NewVideo {
+                uid: existing.uid.clone(),
+                link: existing.link.clone(),
+                title: existing.title.clone(),
+                author: existing.author.clone(),
+                duration: existing.duration,
+                description: existing.description.clone(),
+                thumbnail_path: existing.thumbnail_path.clone(),
+                date: existing.date,
+                other: existing.other.clone(),
+                channel_id: existing.channel_id,
+                album_id: existing.album_id,
+                track: existing.track.clone(),
+            };
+            let candidates: Vec<_> = [stored].into_iter().chain(logged).collect();
+            compare(uid, &candidates, &mut rebuilt.conflicts);
+            let filled = repair(conn, existing.id, &candidates[0], &candidates[1])?;
+            let completed = library::record_metadata(conn, existing.id, &newest.info_dict)?;
+            compare_channel(conn, &newest.info_dict, &mut rebuilt.conflicts)?;
+            if filled || completed {
+                rebuilt.repaired += 1;
+            }
+        }
+    }
+
+    // Same split as when the streams were recorded live, newest of each kind
+    let video = streams.iter().find(|s| s.info_dict.vcodec != "none");
+    let audio = streams
+        .iter()
+        .find(|s| s.info_dict.vcodec == "none" && s.info_dict.acodec != "none");
+    if let Some(moved) = moved_files(newest, video, audio) {
+        rebuilt.files += library::record_files(conn, &moved, video, audio)?;
+    }
+    Ok(())
+}
+
+/// Reports the columns of the stored channel of `info` that it disagrees with, once per channel and column. Channels
+/// are shared between videos and may have been updated by a download newer than the logs, so they're left alone
+fn compare_channel(conn: &mut SqliteConnection, info: &InfoDict, conflicts: &mut Vec<Conflict>) -> Result<()> {
+    let stored: Option<(Option<String>, Option<String>)> = channels::table
+        .filter(channels::uid.eq(&info.channel_id))
+        .select((channels::name, channels::url))
+        .first(conn)
+        .optional()?;
+    let Some((name, url)) = stored else {
+        return Ok(());
+    };
+    for (field, kept, logged) in [
+        ("channels.name", name, &info.channel),
+        ("channels.url", url, &info.channel_url),
+    ] {
+        let Some(kept) = kept.filter(|k| !logged.is_empty() && k != logged) else {
+            continue;
+        };
+        match conflicts
+            .iter_mut()
+            .find(|c| c.uid == info.channel_id && c.field == field)
+        {
+            Some(conflict) if !conflict.ignored.contains(logged) => conflict.ignored.push(logged.clone()),
+            Some(_) => {}
+            None => conflicts.push(Conflict {
+                uid: info.channel_id.clone(),
+                field,
+                kept,
+                ignored: vec![logged.clone()],
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// Fills in the columns of video `id` that are empty in `stored` with what the newest log says
+fn repair(conn: &mut SqliteConnection, id: i64, stored: &NewVideo, logged: &NewVideo) -> Result<bool> {
+    let missing = columns(stored)
+        .iter()
+        .zip(columns(logged))
+        .any(|((_, kept), (_, logged))| kept.is_none() && logged.is_some())
+        || (stored.other.is_none() && logged.other.is_some());
+    if !missing {
+        return Ok(false);
+    }
+    diesel::update(videos::table.find(id))
+        .set((
+            videos::link.eq(stored.link.clone().or(logged.link.clone())),
+            videos::title.eq(stored.title.clone().or(logged.title.clone())),
+            videos::author.eq(stored.author.clone().or(logged.author.clone())),
+            videos::duration.eq(stored.duration.or(logged.duration)),
+            videos::description.eq(stored.description.clone().or(logged.description.clone())),
+            videos::date.eq(stored.date.or(logged.date)),
+            videos::track.eq(stored.track.clone().or(logged.track.clone())),
+            videos::other.eq(stored.other.clone().or(logged.other.clone())),
+        ))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// Works out what yt-dlp's MoveFiles would have reported for a download from its logs and what's on disk: the final
+/// file is the info dict's `filename` if it exists, and thumbnails and subtitles sit next to it under the same name. `None` if the
+/// final file isn't known
+fn moved_files(newest: &DownloadStatus, video: Option<&DownloadStatus>, audio: Option<&DownloadStatus>) -> Option<MovedFiles> {
+    let info = &newest.info_dict;
+    // Whichever is still there, streams that were moved into place keep their temporary name in the log
+    let candidates: Vec<&String> = [&info.filename, &info._filename]
+        .into_iter()
+        .chain(video.or(audio).map(|s| &s.filename))
+        .filter(|f| !f.is_empty())
+        .collect();
+    let filepath = candidates
+        .iter()
+        .find(|f| Path::new(f).exists())
+        .or(candidates.first())?
+        .to_string();
+    let final_path = PathBuf::from(&filepath);
+
+    let mut moved = HashMap::new();
+    // A single stream is moved into place, merged ones are deleted
+    if let (Some(stream), None) | (None, Some(stream)) = (video, audio) {
+        moved.insert(stream.filename.clone(), filepath.clone());
+    }
+    let dir = final_path.parent().unwrap_or(Path::new("."));
+    for thumbnail in info.thumbnails.iter().filter_map(|t| t.filepath.as_ref()) {
+        if let Some(name) = Path::new(thumbnail).file_name() {
+            let candidate = dir.join(name);
+            if candidate.exists() {
+                moved.insert(thumbnail.clone(), candidate.to_string_lossy().into_owned());
+            }
+        }
+    }
+    // Subtitles are written as `<stem>.<lang>.<ext>`
+    if let (Some(stem), Ok(entries)) = (
+        final_path.file_stem().and_then(|s| s.to_str()),
+        fs::read_dir(dir),
+    ) {
+        for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            if path != final_path && name.starts_with(&format!("{}.", stem)) {
+                let path = path.to_string_lossy().into_owned();
+                moved.entry(path.clone()).or_insert(path);
+            }
+        }
+    }
+
+    let format_id = [video, audio]
+        .into_iter()
+        .flatten()
+        .map(|s| s.info_dict.format_id.as_str())
+        .collect::<Vec<_>>()
+        .join("+");
+    Some(MovedFiles {
+        id: info.id.clone(),
+        extractor_key: info.extractor_key.clone(),
+        format_id: Some(format_id).filter(|f| !f.is_empty()),
+        ext: final_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_owned),
+        vcodec: video.map(|v| v.info_dict.vcodec.clone()),
+        acodec: audio.or(video).map(|a| a.info_dict.acodec.clone()),
+        filepath,
+        moved,
+    })
+}
@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use humansize::{format_size, DECIMAL};
+use log::{info, warn};
+use serde::Serialize;
+use std::{collections::BTreeSet, fs, time::Duration};
+
+use crate::comms::ErrorKind;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedLink {
+    pub link: String,
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Skipped by later runs until rechecked
+    pub permanent: bool,
+}
+
+/// What happened to the links handed to workers during one `download` run
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    /// Unix seconds
+    pub started_at: i64,
+    /// Seconds from reading the input to the last worker exiting
+    pub wall_time: f64,
+    pub downloaded: usize,
+    pub skipped: usize,
+    /// Failed attempts that were put back in line, whether or not a later one worked
+    pub retried: usize,
+    /// Bytes yt-dlp actually fetched, files it found already downloaded don't count
+    pub bytes: u64,
+    pub failed: Vec<FailedLink>,
+    /// Links yt-dlp downloaded without reporting a single file at its final place, which points at a post-processor
+    /// hook that stopped matching
+    pub without_files: Vec<String>,
+    /// Links yt-dlp downloaded whose info couldn't be read, not recorded until the quarantine is replayed
+    pub quarantined: Vec<String>,
+    /// Info dict keys yt-dlp sent that we don't know about yet, see `InfoDict::unknown_fields`
+    pub schema_drift: BTreeSet<String>,
+}
+
+impl RunSummary {
+    pub fn log(&self) {
+        info!(
+            "Done in {:.1?}: {} downloaded ({}), {} skipped, {} failed, {} retries",
+            Duration::from_secs_f64(self.wall_time),
+            self.downloaded,
+            format_size(self.bytes, DECIMAL),
+            self.skipped,
+            self.failed.len(),
+            self.retried
+        );
+        for f in &self.failed {
+            let permanent = if f.permanent { ", permanent" } else { "" };
+            warn!(
+                "  {} ({}{}): {}",
+                f.link,
+                f.kind.as_str(),
+                permanent,
+                f.message
+            );
+        }
+        if !self.without_files.is_empty() {
+            warn!(
+                "{} downloads reported no files, they won't be in the files table:",
+                self.without_files.len()
+            );
+            for link in &self.without_files {
+                warn!("  {}", link);
+            }
+        }
+        if !self.quarantined.is_empty() {
+            warn!(
+                "{} downloads aren't recorded because their info was quarantined, see `replay-quarantine`:",
+                self.quarantined.len()
+            );
+            for link in &self.quarantined {
+                warn!("  {}", link);
+            }
+        }
+    }
+
+    /// Writes the summary as pretty JSON to `path`
+    pub fn write(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).with_context(|| format!("Unable to write the run report to {}", path))
+    }
+}
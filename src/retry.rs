@@ -0,0 +1,170 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    time::{Duration, SystemTime},
+};
+
+use crate::comms::ErrorKind;
+
+/// Lowercased bits of yt-dlp error messages for videos that won't ever download, however often we try
+const PERMANENT_ERRORS: [&str; 11] = [
+    "private video",
+    "video unavailable",
+    "has been removed",
+    "no longer available",
+    "copyright",
+    "members-only",
+    "members only",
+    "join this channel",
+    "account associated with this video has been terminated",
+    "this live event has ended",
+    "unsupported url",
+];
+/// Lowercased bits that make a failure worth retrying even if it otherwise looks permanent, e.g. a rate limit
+/// reported as "Video unavailable"
+const TRANSIENT_ERRORS: [&str; 9] = [
+    "http error 429",
+    "http error 5",
+    "too many requests",
+    "timed out",
+    "timeout",
+    "temporary failure",
+    "connection reset",
+    "try again later",
+    "not a bot",
+];
+
+/// What to do with a link after a failed attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Back in line once `retry_at` (unix seconds) has passed
+    Retry { retry_at: i64 },
+    /// Out of attempts, tried again on the next run
+    GiveUp,
+    /// Won't ever work, skipped until the user asks for a recheck
+    Permanent,
+}
+
+/// Whether a failure is down to the video itself rather than to the network, the site having a bad day or us
+pub fn is_permanent(kind: ErrorKind, message: &str) -> bool {
+    let message = message.to_lowercase();
+    if TRANSIENT_ERRORS.iter().any(|e| message.contains(e)) {
+        return false;
+    }
+    match kind {
+        ErrorKind::GeoRestricted | ErrorKind::Unsupported => true,
+        ErrorKind::Extractor | ErrorKind::Download | ErrorKind::Other => PERMANENT_ERRORS.iter().any(|e| message.contains(e)),
+        ErrorKind::Network | ErrorKind::Postprocessing | ErrorKind::Cancelled => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Tries per link and run, the first one included
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every one after it
+    pub backoff: Duration,
+    /// Random spread of the delay, as a fraction of it
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Decides what happens to `link` after it failed its `attempts`th try
+    pub fn verdict(&self, link: &str, kind: ErrorKind, message: &str, attempts: u32) -> Verdict {
+        // Cancelled on purpose, retrying would undo that
+        if kind == ErrorKind::Cancelled {
+            return Verdict::GiveUp;
+        }
+        if is_permanent(kind, message) {
+            return Verdict::Permanent;
+        }
+        if attempts >= self.max_attempts {
+            return Verdict::GiveUp;
+        }
+        Verdict::Retry {
+            retry_at: crate::queue::unix_now() + self.delay(link, attempts).as_secs() as i64,
+        }
+    }
+
+    fn delay(&self, link: &str, attempts: u32) -> Duration {
+        let base = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)));
+        // Spreads out links that failed together (e.g. on a rate limit) so they don't all come back at once. Std's
+        // hasher is randomly keyed, which is all the randomness this needs
+        let seed = (link, SystemTime::now());
+        let unit = RandomState::new().hash_one(seed) as f64 / u64::MAX as f64;
+        base.mul_f64((1.0 + self.jitter * (2.0 * unit - 1.0)).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        backoff: Duration::from_secs(60),
+        jitter: 0.25,
+    };
+
+    #[test]
+    fn transient_wins_over_permanent() {
+        assert!(is_permanent(ErrorKind::Extractor, "[youtube] x: Video unavailable"));
+        assert!(!is_permanent(
+            ErrorKind::Extractor,
+            "[youtube] x: Video unavailable. This content isn't available, try again later"
+        ));
+        assert!(!is_permanent(ErrorKind::Download, "Private video? HTTP Error 429: Too Many Requests"));
+        // Geo restrictions are permanent whatever the message says, unless it says to come back
+        assert!(is_permanent(ErrorKind::GeoRestricted, "not available in your country"));
+        assert!(!is_permanent(ErrorKind::GeoRestricted, "Sign in to confirm you're not a bot"));
+        assert!(!is_permanent(ErrorKind::Network, "Video unavailable"));
+
+        let verdict = POLICY.verdict("x", ErrorKind::Extractor, "Video unavailable, try again later", 1);
+        assert!(matches!(verdict, Verdict::Retry { .. }));
+    }
+
+    #[test]
+    fn attempts_run_out() {
+        let verdict = |attempts| POLICY.verdict("x", ErrorKind::Network, "HTTP Error 503", attempts);
+        assert!(matches!(verdict(1), Verdict::Retry { .. }));
+        assert!(matches!(verdict(2), Verdict::Retry { .. }));
+        assert_eq!(verdict(3), Verdict::GiveUp);
+        assert_eq!(verdict(4), Verdict::GiveUp);
+        // Permanent failures don't use up attempts, cancelled ones are never retried
+        assert_eq!(
+            POLICY.verdict("x", ErrorKind::Extractor, "Private video", 1),
+            Verdict::Permanent
+        );
+        assert_eq!(
+            POLICY.verdict("x", ErrorKind::Cancelled, "Cancelled by the master", 1),
+            Verdict::GiveUp
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_within_jitter() {
+        let exact = RetryPolicy { jitter: 0.0, ..POLICY };
+        for (attempts, secs) in [(1, 60), (2, 120), (3, 240), (4, 480)] {
+            assert_eq!(exact.delay("x", attempts), Duration::from_secs(secs));
+            for link in ["a", "b", "c", "d"] {
+                let delay = POLICY.delay(link, attempts).as_secs_f64();
+                assert!(
+                    (secs as f64 * 0.75..=secs as f64 * 1.25).contains(&delay),
+                    "{}s for attempt {}",
+                    delay,
+                    attempts
+                );
+            }
+        }
+        // Huge attempt counts saturate rather than overflow
+        assert!(exact.delay("x", 200) >= exact.delay("x", 20));
+
+        let before = crate::queue::unix_now();
+        let Verdict::Retry { retry_at } = POLICY.verdict("x", ErrorKind::Network, "timed out", 2) else {
+            panic!("not retried");
+        };
+        assert!((before + 90..=crate::queue::unix_now() + 150).contains(&retry_at));
+    }
+}
@@ -9,5 +9,124 @@ diesel::table! {
         author -> Nullable<Text>,
         duration -> Nullable<BigInt>,
         description -> Nullable<Text>,
+        thumbnail_path -> Nullable<Text>,
+        date -> Nullable<BigInt>,
+        other -> Nullable<Binary>,
+        channel_id -> Nullable<BigInt>,
+        album_id -> Nullable<BigInt>,
+        track -> Nullable<Text>,
     }
 }
+
+diesel::table! {
+    videos_duplicates (id) {
+        id -> BigInt,
+        merged_into -> BigInt,
+        uid -> Nullable<Text>,
+        link -> Nullable<Text>,
+        title -> Nullable<Text>,
+        author -> Nullable<Text>,
+        duration -> Nullable<BigInt>,
+        description -> Nullable<Text>,
+        thumbnail_path -> Nullable<Text>,
+        date -> Nullable<BigInt>,
+        other -> Nullable<Binary>,
+    }
+}
+
+diesel::table! {
+    queue (id) {
+        id -> BigInt,
+        uid -> Text,
+        link -> Text,
+        title -> Nullable<Text>,
+        channel -> Nullable<Text>,
+        position -> Nullable<BigInt>,
+        status -> Text,
+        attempts -> BigInt,
+        last_error -> Nullable<Text>,
+        worker_id -> Nullable<BigInt>,
+        created_at -> BigInt,
+        updated_at -> BigInt,
+        error_kind -> Nullable<Text>,
+        retry_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    channels (id) {
+        id -> BigInt,
+        uid -> Text,
+        name -> Nullable<Text>,
+        url -> Nullable<Text>,
+        follower_count -> Nullable<BigInt>,
+        verified -> Bool,
+    }
+}
+
+diesel::table! {
+    albums (id) {
+        id -> BigInt,
+        title -> Text,
+        artist -> Text,
+        release_year -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    tags (id) {
+        id -> BigInt,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    video_tags (video_id, tag_id) {
+        video_id -> BigInt,
+        tag_id -> BigInt,
+    }
+}
+
+diesel::table! {
+    categories (id) {
+        id -> BigInt,
+        video_id -> BigInt,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    chapters (id) {
+        id -> BigInt,
+        video_id -> BigInt,
+        position -> BigInt,
+        title -> Nullable<Text>,
+        start_time -> Double,
+        end_time -> Double,
+    }
+}
+
+diesel::table! {
+    files (id) {
+        id -> BigInt,
+        video_id -> BigInt,
+        path -> Text,
+        role -> Text,
+        size -> Nullable<BigInt>,
+        container -> Nullable<Text>,
+        codec -> Nullable<Text>,
+        format_id -> Nullable<Text>,
+        checksum -> Nullable<Text>,
+        mtime -> Nullable<BigInt>,
+    }
+}
+
+diesel::joinable!(videos -> channels (channel_id));
+diesel::joinable!(videos -> albums (album_id));
+diesel::joinable!(video_tags -> videos (video_id));
+diesel::joinable!(video_tags -> tags (tag_id));
+diesel::joinable!(categories -> videos (video_id));
+diesel::joinable!(chapters -> videos (video_id));
+diesel::joinable!(files -> videos (video_id));
+
+diesel::allow_tables_to_appear_in_same_query!(albums, categories, channels, chapters, files, queue, tags, video_tags, videos, videos_duplicates,);
@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Nullable, Text},
+    sqlite::SqliteConnection,
+};
+use serde::Serialize;
+
+use crate::comms::{OutputFormat, QueryOptions, SortKey};
+
+const TABLE_TITLE_WIDTH: usize = 60;
+const TABLE_CHANNEL_WIDTH: usize = 24;
+
+/// One row of `list`/`search` output
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct LibraryEntry {
+    #[diesel(sql_type = Text)]
+    pub uid: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub title: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub author: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub channel: Option<String>,
+    /// Seconds
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub duration: Option<i64>,
+    /// Upload date as YYYYMMDD
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub date: Option<i64>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub link: Option<String>,
+    /// Best file we have for the video: merged, then video, then audio
+    #[diesel(sql_type = Nullable<Text>)]
+    pub path: Option<String>,
+}
+
+/// Runs `list` (no `search`) or `search` with the filters from `options`
+pub fn query(conn: &mut SqliteConnection, search: Option<&str>, options: &QueryOptions) -> Result<Vec<LibraryEntry>> {
+    // Boxed raw queries only carry bind values, every `?` has to be written out next to its `bind`
+    let mut query = sql_query(
+        "SELECT v.uid, v.title, v.author, c.name AS channel, v.duration, v.date, v.link, \
+            (SELECT f.path FROM files f WHERE f.video_id = v.id AND f.role IN ('merged', 'video', 'audio') \
+                ORDER BY CASE f.role WHEN 'merged' THEN 0 WHEN 'video' THEN 1 ELSE 2 END LIMIT 1) AS path \
+        FROM videos v LEFT JOIN channels c ON c.id = v.channel_id",
+    )
+    .into_boxed();
+
+    if let Some(search) = search {
+        query = query
+            .sql(" JOIN videos_fts ON videos_fts.rowid = v.id WHERE videos_fts MATCH ?")
+            .bind::<Text, _>(search.to_owned());
+    } else {
+        query = query.sql(" WHERE 1");
+    }
+
+    if let Some(channel) = &options.channel {
+        query = query
+            .sql(" AND (c.uid = ?")
+            .bind::<Text, _>(channel.clone())
+            .sql(" OR c.name LIKE '%' || ? || '%')")
+            .bind::<Text, _>(channel.clone());
+    }
+    let ranges = [
+        ("v.duration >= ?", options.min_duration),
+        ("v.duration <= ?", options.max_duration),
+        ("v.date >= ?", options.after),
+        ("v.date <= ?", options.before),
+    ];
+    for (condition, value) in ranges {
+        if let Some(value) = value {
+            query = query.sql(" AND ").sql(condition).bind::<BigInt, _>(value);
+        }
+    }
+
+    let sort = match (options.sort, search) {
+        (Some(SortKey::Relevance), None) | (None, None) => SortKey::Date,
+        (None, Some(_)) => SortKey::Relevance,
+        (Some(sort), _) => sort,
+    };
+    // (expression, whether the natural order is descending)
+    let (order, descending) = match sort {
+        SortKey::Relevance => ("bm25(videos_fts)", false),
+        SortKey::Date => ("v.date", true),
+        SortKey::Title => ("v.title COLLATE NOCASE", false),
+        SortKey::Channel => ("c.name COLLATE NOCASE", false),
+        SortKey::Duration => ("v.duration", true),
+        SortKey::Added => ("v.id", true),
+    };
+    let direction = if descending != options.reverse {
+        "DESC"
+    } else {
+        "ASC"
+    };
+    query = query
+        .sql(" ORDER BY ")
+        .sql(order)
+        .sql(" ")
+        .sql(direction)
+        .sql(" NULLS LAST, v.id ")
+        .sql(direction);
+
+    if let Some(limit) = options.limit {
+        query = query.sql(" LIMIT ?").bind::<BigInt, _>(limit as i64);
+    }
+
+    query.load(conn).context(match search {
+        Some(_) => "Unable to search the library, check the query syntax",
+        None => "Unable to list the library",
+    })
+}
+
+/// YYYYMMDD -> YYYY-MM-DD
+fn format_date(date: Option<i64>) -> String {
+    date.map(|d| format!("{:04}-{:02}-{:02}", d / 10000, d / 100 % 100, d % 100))
+        .unwrap_or_default()
+}
+
+fn format_duration(duration: Option<i64>) -> String {
+    match duration {
+        Some(d) if d >= 3600 => format!("{}:{:02}:{:02}", d / 3600, d / 60 % 60, d % 60),
+        Some(d) => format!("{}:{:02}", d / 60, d % 60),
+        None => String::new(),
+    }
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_owned();
+    }
+    s.chars().take(width - 1).chain(['…']).collect()
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+pub fn print(entries: &[LibraryEntry], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(entries)?),
+        OutputFormat::Csv => {
+            println!("uid,title,author,channel,duration,date,link,path");
+            for e in entries {
+                let fields = [
+                    csv_field(&e.uid),
+                    csv_field(e.title.as_deref().unwrap_or_default()),
+                    csv_field(e.author.as_deref().unwrap_or_default()),
+                    csv_field(e.channel.as_deref().unwrap_or_default()),
+                    e.duration.map(|d| d.to_string()).unwrap_or_default(),
+                    e.date.map(|d| d.to_string()).unwrap_or_default(),
+                    csv_field(e.link.as_deref().unwrap_or_default()),
+                    csv_field(e.path.as_deref().unwrap_or_default()),
+                ];
+                println!("{}", fields.join(","));
+            }
+        }
+        OutputFormat::Table => {
+            let rows: Vec<[String; 5]> = entries
+                .iter()
+                .map(|e| {
+                    [
+                        format_date(e.date),
+                        format_duration(e.duration),
+                        truncate(
+                            e.author
+                                .as_deref()
+                                .or(e.channel.as_deref())
+                                .unwrap_or_default(),
+                            TABLE_CHANNEL_WIDTH,
+                        ),
+                        truncate(e.title.as_deref().unwrap_or_default(), TABLE_TITLE_WIDTH),
+                        e.uid.clone(),
+                    ]
+                })
+                .collect();
+            let header = ["DATE", "LENGTH", "AUTHOR", "TITLE", "ID"].map(str::to_owned);
+
+            let mut widths = [0; 5];
+            for row in std::iter::once(&header).chain(&rows) {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            for row in std::iter::once(&header).chain(&rows) {
+                let line: Vec<String> = row
+                    .iter()
+                    .zip(widths)
+                    .enumerate()
+                    // Durations read better right-aligned
+                    .map(|(i, (cell, width))| match i {
+                        1 => format!("{:>width$}", cell),
+                        _ => format!("{:<width$}", cell),
+                    })
+                    .collect();
+                println!("{}", line.join("  ").trim_end());
+            }
+            println!("{} videos", entries.len());
+        }
+    }
+    Ok(())
+}
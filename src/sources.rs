@@ -0,0 +1,315 @@
+use anyhow::{Context, Result};
+use log::debug;
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    sync::LazyLock,
+};
+
+use crate::comms::InputFormat;
+use crate::links::LinkExtractor;
+use crate::page::PageSource;
+
+/// Path that makes us read the input from stdin
+pub const STDIN_PATH: &str = "-";
+
+static XSPF_LOCATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<location>\s*(.*?)\s*</location>").unwrap());
+
+/// A link with whatever the source knew about it before downloading
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceEntry {
+    pub link: String,
+    pub title: Option<String>,
+    pub channel: Option<String>,
+    /// 1-based position in the source playlist
+    pub position: Option<usize>,
+}
+
+impl From<String> for SourceEntry {
+    fn from(link: String) -> Self {
+        SourceEntry {
+            link,
+            ..Default::default()
+        }
+    }
+}
+
+/// Anything we can get a list of links out of
+pub trait LinkSource {
+    /// Returns raw links in order of appearance, normalization and deduplication happen later
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>>;
+}
+
+/// Saved HTML page, scanned with the link regex
+pub struct HtmlSource(pub LinkExtractor);
+
+impl LinkSource for HtmlSource {
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>> {
+        Ok(self
+            .0
+            .extract(content)
+            .into_iter()
+            .map(SourceEntry::from)
+            .collect())
+    }
+}
+
+/// One URL or ID per line, blank lines and `#` comments are ignored
+pub struct ListSource;
+
+impl LinkSource for ListSource {
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>> {
+        Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| SourceEntry::from(l.to_owned()))
+            .collect())
+    }
+}
+
+/// M3U/M3U8 playlist, every non-directive line is a location
+pub struct M3uSource;
+
+impl LinkSource for M3uSource {
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>> {
+        // Same rules as a plain list, `#EXTM3U`/`#EXTINF` directives look like comments
+        ListSource.links(content.trim_start_matches('\u{feff}'))
+    }
+}
+
+/// XSPF playlist, links come from `<track><location>`
+pub struct XspfSource;
+
+impl LinkSource for XspfSource {
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>> {
+        Ok(XSPF_LOCATION
+            .captures_iter(content)
+            .map(|caps| SourceEntry::from(unescape_xml(&caps[1])))
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct TakeoutHistoryItem {
+    #[serde(rename = "titleUrl")]
+    title_url: Option<String>,
+}
+
+/// Google Takeout `watch-history.json`
+pub struct TakeoutHistorySource;
+
+impl LinkSource for TakeoutHistorySource {
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>> {
+        let items: Vec<TakeoutHistoryItem> = serde_json::from_str(content).context("Unable to parse Takeout watch history")?;
+        // Removed videos and ads have no titleUrl
+        Ok(items
+            .into_iter()
+            .filter_map(|i| i.title_url)
+            .map(SourceEntry::from)
+            .collect())
+    }
+}
+
+/// Google Takeout playlist CSV export, older exports have a metadata block above the video table
+pub struct TakeoutCsvSource;
+
+impl LinkSource for TakeoutCsvSource {
+    fn links(&self, content: &str) -> Result<Vec<SourceEntry>> {
+        let mut lines = content.lines().map(str::trim);
+        // Both "Video Id" (old) and "Video ID" (new) headers are around
+        lines
+            .find(|l| {
+                l.split(',')
+                    .next()
+                    .is_some_and(|c| c.trim_matches('"').eq_ignore_ascii_case("video id"))
+            })
+            .context("Unable to find the \"Video ID\" column in Takeout CSV")?;
+
+        Ok(lines
+            .filter(|l| !l.is_empty())
+            .filter_map(|l| l.split(',').next())
+            .map(|c| SourceEntry::from(c.trim_matches('"').to_owned()))
+            .collect())
+    }
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Reads the whole input, `-` means stdin
+pub fn read_input(path: &str) -> Result<String> {
+    let mut content = String::new();
+    if path == STDIN_PATH {
+        io::stdin()
+            .read_to_string(&mut content)
+            .context("Unable to read input from stdin")?;
+    } else {
+        content = fs::read_to_string(path).with_context(|| format!("Unable to read input {}", path))?;
+    }
+    debug!("Read {} bytes from {}", content.len(), path);
+    Ok(content)
+}
+
+/// Guesses the format from the extension first and the content second
+pub fn detect_format(path: &str, content: &str) -> InputFormat {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+
+    let is_youtube_page = content.contains("ytInitialData") || content.contains("initialData.push");
+
+    match ext.as_deref() {
+        // Saved YouTube/YouTube Music pages are better served by the DOM parser than by the regex
+        Some("html" | "htm") if is_youtube_page => return InputFormat::Page,
+        Some("html" | "htm") => return InputFormat::Html,
+        Some("m3u" | "m3u8") => return InputFormat::M3u,
+        Some("xspf") => return InputFormat::Xspf,
+        Some("json") => return InputFormat::TakeoutJson,
+        Some("csv") => return InputFormat::TakeoutCsv,
+        Some("txt" | "list") => return InputFormat::List,
+        _ => {}
+    }
+
+    let head = content.trim_start_matches('\u{feff}').trim_start();
+    let head_lower = head.chars().take(1024).collect::<String>().to_lowercase();
+    if head.starts_with("#EXTM3U") {
+        InputFormat::M3u
+    } else if head_lower.contains("<playlist") && head_lower.contains("xspf") {
+        InputFormat::Xspf
+    } else if head.starts_with('[') {
+        InputFormat::TakeoutJson
+    } else if is_youtube_page {
+        InputFormat::Page
+    } else if head_lower.contains("<html") || head_lower.starts_with("<!doctype") {
+        InputFormat::Html
+    } else if head_lower
+        .lines()
+        .any(|l| l.trim_matches('"').starts_with("video id"))
+    {
+        InputFormat::TakeoutCsv
+    } else {
+        InputFormat::List
+    }
+}
+
+pub fn source_for(format: InputFormat, extractor: LinkExtractor) -> Box<dyn LinkSource> {
+    match format {
+        InputFormat::Html => Box::new(HtmlSource(extractor)),
+        InputFormat::Page => Box::new(PageSource(extractor)),
+        InputFormat::List => Box::new(ListSource),
+        InputFormat::M3u => Box::new(M3uSource),
+        InputFormat::Xspf => Box::new(XspfSource),
+        InputFormat::TakeoutJson => Box::new(TakeoutHistorySource),
+        InputFormat::TakeoutCsv => Box::new(TakeoutCsvSource),
+        InputFormat::Auto => unreachable!("Input format should be detected before picking a source"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M3U: &str = "\u{feff}#EXTM3U\n#EXTINF:242,Harbour - Harbour Lights\nhttps://youtu.be/Xq1Hm3t7bKc\n\n#EXTINF:-1,Rick Astley - Never Gonna Give You Up\nhttps://www.youtube.com/watch?v=dQw4w9WgXcQ\n";
+    const XSPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <trackList>
+    <track><title>Harbour Lights</title><location>
+      https://www.youtube.com/watch?v=Xq1Hm3t7bKc&amp;list=PL1
+    </location></track>
+    <track><location>https://youtu.be/dQw4w9WgXcQ</location></track>
+  </trackList>
+</playlist>"#;
+    const TAKEOUT_JSON: &str = r#"[
+  {"header": "YouTube", "title": "Watched Harbour Lights", "titleUrl": "https://www.youtube.com/watch?v=Xq1Hm3t7bKc"},
+  {"header": "YouTube", "title": "Watched a video that has been removed"},
+  {"header": "YouTube", "title": "Watched Never Gonna Give You Up", "titleUrl": "https://www.youtube.com/watch?v=dQw4w9WgXcQ", "details": [{"name": "From Google Ads"}]}
+]"#;
+    // Older exports have the playlist's own metadata above the video table
+    const TAKEOUT_CSV: &str = "Playlist Id,Add new videos to top,Playlist Visibility\nPL1,False,Private\n\n\"Video Id\",\"Time Added\"\nXq1Hm3t7bKc,2024-01-01 00:00:00 UTC\n\"dQw4w9WgXcQ\",2024-01-02 00:00:00 UTC\n";
+    const LIST: &str = "# liked\nhttps://youtu.be/Xq1Hm3t7bKc\n\n  dQw4w9WgXcQ  \n";
+    const HTML: &str = "<!DOCTYPE html><html><body><a href=\"https://www.youtube.com/watch?v=Xq1Hm3t7bKc\">x</a> <a href=\"/watch?v=dQw4w9WgXcQ\">y</a></body></html>";
+
+    fn links(format: InputFormat, content: &str) -> Vec<String> {
+        let extractor = LinkExtractor::new(r"watch\?v=(?P<id>[\w-]{11})").unwrap();
+        source_for(format, extractor)
+            .links(content)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.link)
+            .collect()
+    }
+
+    #[test]
+    fn formats_by_extension() {
+        for (path, format) in [
+            ("saved.HTML", InputFormat::Html),
+            ("saved.htm", InputFormat::Html),
+            ("mix.m3u8", InputFormat::M3u),
+            ("mix.xspf", InputFormat::Xspf),
+            ("Takeout/watch-history.json", InputFormat::TakeoutJson),
+            ("Takeout/liked.csv", InputFormat::TakeoutCsv),
+            ("links.txt", InputFormat::List),
+            ("links.list", InputFormat::List),
+        ] {
+            assert_eq!(detect_format(path, ""), format, "{}", path);
+        }
+        // The extension says HTML, the content says it's a YouTube page
+        assert_eq!(
+            detect_format("playlist.html", "<script>var ytInitialData = {};</script>"),
+            InputFormat::Page
+        );
+    }
+
+    #[test]
+    fn formats_by_content() {
+        for (content, format) in [
+            (M3U, InputFormat::M3u),
+            (XSPF, InputFormat::Xspf),
+            (TAKEOUT_JSON, InputFormat::TakeoutJson),
+            (TAKEOUT_CSV, InputFormat::TakeoutCsv),
+            (HTML, InputFormat::Html),
+            ("<script>initialData.push({data: '\\x7b\\x7d'});</script>", InputFormat::Page),
+            (LIST, InputFormat::List),
+        ] {
+            assert_eq!(detect_format(STDIN_PATH, content), format, "{:.40}", content);
+        }
+    }
+
+    #[test]
+    fn links_in_order() {
+        assert_eq!(
+            links(InputFormat::M3u, M3U),
+            ["https://youtu.be/Xq1Hm3t7bKc", "https://www.youtube.com/watch?v=dQw4w9WgXcQ"]
+        );
+        assert_eq!(
+            links(InputFormat::Xspf, XSPF),
+            ["https://www.youtube.com/watch?v=Xq1Hm3t7bKc&list=PL1", "https://youtu.be/dQw4w9WgXcQ"]
+        );
+        assert_eq!(
+            links(InputFormat::TakeoutJson, TAKEOUT_JSON),
+            ["https://www.youtube.com/watch?v=Xq1Hm3t7bKc", "https://www.youtube.com/watch?v=dQw4w9WgXcQ"]
+        );
+        assert_eq!(links(InputFormat::TakeoutCsv, TAKEOUT_CSV), ["Xq1Hm3t7bKc", "dQw4w9WgXcQ"]);
+        assert_eq!(links(InputFormat::List, LIST), ["https://youtu.be/Xq1Hm3t7bKc", "dQw4w9WgXcQ"]);
+        assert_eq!(links(InputFormat::Html, HTML), ["Xq1Hm3t7bKc", "dQw4w9WgXcQ"]);
+    }
+
+    #[test]
+    fn broken_takeout_exports() {
+        let extractor = || LinkExtractor::new(r"(?P<id>x)").unwrap();
+        assert!(source_for(InputFormat::TakeoutJson, extractor()).links("{").is_err());
+        assert!(source_for(InputFormat::TakeoutCsv, extractor())
+            .links("Playlist Id,Visibility\nPL1,Private\n")
+            .is_err());
+    }
+}
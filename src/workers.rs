@@ -0,0 +1,903 @@
+use anyhow::{Context, Result};
+use diesel::sqlite::SqliteConnection;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{debug, error, info, log, warn};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+    process::{Child, Command},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    time::{self, Instant},
+};
+
+use crate::comms::{
+    self, AsyncMessageRead, AsyncMessageWrite, Capabilities, DownloadStatus, FrameError, Greeting, InfoDict, Message, MovedFiles, Outcome, PlaylistEntry, Status,
+    HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, MASTER_ACCEPTS, WORKER_ACCEPTS,
+};
+use crate::library;
+use crate::links::VideoKey;
+use crate::quarantine::{self, PayloadKind, Quarantined};
+use crate::queue::{self, LinkQueue, QueueStatus};
+use crate::report;
+use crate::retry::{RetryPolicy, Verdict};
+use crate::sources::SourceEntry;
+
+/// How long a worker told to stop right away gets to interrupt its download and disconnect before it's killed
+const SHUTDOWN_GRACE: Duration = HEARTBEAT_INTERVAL;
+
+/// Greeted connections waiting for the slot that spawned their worker, `Err` with the reason if it was turned away
+pub type Arrivals = Arc<Mutex<HashMap<usize, oneshot::Sender<Result<UnixStream, String>>>>>;
+
+/// Starts worker processes, which all get the same environment apart from their thread ID, and kills them
+pub struct Spawner {
+    pub exe: PathBuf,
+    pub env: Vec<(&'static str, String)>,
+    /// Process groups of the workers that haven't been reaped yet by thread ID, each worker leads its own
+    pub groups: Mutex<HashMap<usize, u32>>,
+}
+
+impl Spawner {
+    fn spawn(&self, thr_id: usize) -> Result<Child> {
+        let mut command = std::process::Command::new(&self.exe);
+        command
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .env("THR_ID", thr_id.to_string())
+            // Keeps Ctrl-C from reaching workers directly, the master decides how they stop
+            .process_group(0);
+        let child = Command::from(command)
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Unable to spawn thread {}", thr_id))?;
+        if let Some(pid) = child.id() {
+            self.groups.lock().unwrap().insert(thr_id, pid);
+        }
+        Ok(child)
+    }
+
+    /// SIGKILLs worker `thr_id` along with whatever it started, e.g. ffmpeg, which killing the worker alone would leave
+    /// running
+    fn kill(&self, thr_id: usize) {
+        if let Some(&pid) = self.groups.lock().unwrap().get(&thr_id) {
+            kill_group(pid);
+        }
+    }
+
+    /// Forgets worker `thr_id` once it's reaped, after which its process group ID may be reused
+    fn reaped(&self, thr_id: usize) {
+        self.groups.lock().unwrap().remove(&thr_id);
+    }
+
+    /// SIGKILLs every worker and whatever they started, for when the master exits without waiting for them
+    pub fn kill_all(&self) {
+        for &pid in self.groups.lock().unwrap().values() {
+            kill_group(pid);
+        }
+    }
+}
+
+fn kill_group(pid: u32) {
+    // SAFETY: killpg has no memory safety requirements. The group is still ours, its leader hasn't been reaped
+    if unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) } != 0 {
+        debug!("Unable to kill process group {}: {}", pid, std::io::Error::last_os_error());
+    }
+}
+
+/// What every worker connection is served with
+pub struct Shared {
+    pub connection: Mutex<SqliteConnection>,
+    pub queue: Mutex<LinkQueue>,
+    pub summary: Mutex<report::RunSummary>,
+    pub mp: Arc<Mutex<MultiProgress>>,
+    pub pb_style: ProgressStyle,
+    pub logs_dir: String,
+    pub batch_size: usize,
+    pub max_frame_size: usize,
+    pub inactivity_timeout: Duration,
+    pub retry: RetryPolicy,
+    pub strict_schema: bool,
+    pub stop: watch::Sender<Stop>,
+    /// Whether the workers are told to hold their downloads
+    pub paused: watch::Sender<bool>,
+    /// Outboxes of the connected workers by thread ID, for pushing control messages to them
+    pub controls: Mutex<HashMap<usize, UnboundedSender<Message>>>,
+}
+
+impl Shared {
+    /// Moves the run on to `stop` and tells every connected worker to finish its download and disconnect, or to
+    /// interrupt it if it's being killed
+    pub fn set_stop(&self, stop: Stop) {
+        self.stop.send_replace(stop);
+        let graceful = stop == Stop::Draining;
+        for (thr_id, control) in self.controls.lock().unwrap().iter() {
+            // A worker that just disconnected is being taken care of already
+            if control.send(Message::Shutdown { graceful }).is_err() {
+                debug!("Thread {} is gone, not telling it to stop", thr_id);
+            }
+        }
+    }
+
+    /// Holds or lets go of every worker's download, workers connecting later are told as well
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+        for control in self.controls.lock().unwrap().values() {
+            let _ = control.send(if paused { Message::Pause } else { Message::Resume });
+        }
+    }
+}
+
+/// How far along stopping the run is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Running,
+    /// No more batches, workers finish the link they're on and disconnect
+    Draining,
+    /// Workers interrupt their downloads and disconnect, or get killed if they don't. Whatever they held goes back
+    /// in line
+    Killing,
+}
+
+/// How a worker's connection ended
+enum Ended {
+    /// Out of work, or turned away. Not worth replacing
+    Finished,
+    /// Died, hung or never showed up. Killed and replaced
+    Lost,
+}
+
+/// Resolves once the run is being killed
+async fn killing(stop: &mut watch::Receiver<Stop>) {
+    // The sender lives in `Shared`, which outlives every supervisor
+    let _ = stop.wait_for(|s| *s == Stop::Killing).await;
+}
+
+/// Keeps worker `thr_id` going until it runs out of work: spawns it, serves its connection and reaps it, replacing it
+/// up to `max_respawns` times if it dies, hangs or never connects
+pub async fn supervise(thr_id: usize, spawner: Arc<Spawner>, arrivals: Arrivals, shared: Arc<Shared>, max_respawns: usize) {
+    let mut respawns = 0;
+    let mut stop = shared.stop.subscribe();
+    loop {
+        let (arrival, arrived) = oneshot::channel();
+        arrivals.lock().unwrap().insert(thr_id, arrival);
+        let mut child = match spawner.spawn(thr_id) {
+            Ok(child) => child,
+            Err(e) => {
+                error!("{:#}", e);
+                return;
+            }
+        };
+
+        let connected = tokio::select! {
+            arrived = arrived => match arrived {
+                Ok(Ok(stream)) => Ok(stream),
+                // Replacing an incompatible worker with the same executable won't help
+                Ok(Err(_)) => Err(Ended::Finished),
+                // The accept loop is gone, nobody is going to hand us a connection
+                Err(_) => Err(Ended::Lost),
+            },
+            _ = child.wait() => {
+                warn!("Thread {} exited before connecting", thr_id);
+                Err(Ended::Lost)
+            }
+            _ = time::sleep(HEARTBEAT_TIMEOUT) => {
+                error!("Thread {} did not connect within {:?}", thr_id, HEARTBEAT_TIMEOUT);
+                Err(Ended::Lost)
+            }
+            _ = killing(&mut stop) => Err(Ended::Lost),
+        };
+        arrivals.lock().unwrap().remove(&thr_id);
+
+        let ended = match connected {
+            Ok(stream) => {
+                let serving = serve(stream, thr_id, &shared);
+                tokio::pin!(serving);
+                tokio::select! {
+                    ended = &mut serving => ended,
+                    // It's been told to stop right away. If it doesn't, its connection breaks once it's dead, which
+                    // has `serve` requeue what it held
+                    _ = killing(&mut stop) => match time::timeout(SHUTDOWN_GRACE, &mut serving).await {
+                        Ok(ended) => ended,
+                        Err(_) => {
+                            warn!("Thread {} didn't stop within {:?}, killing it", thr_id, SHUTDOWN_GRACE);
+                            spawner.kill(thr_id);
+                            serving.await
+                        }
+                    }
+                }
+            }
+            Err(ended) => ended,
+        };
+
+        if let Ended::Lost = ended {
+            // Nothing happens if it's dead already
+            spawner.kill(thr_id);
+        }
+        let status = match time::timeout(HEARTBEAT_TIMEOUT, child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                warn!("Thread {} is taking too long to exit, killing it", thr_id);
+                spawner.kill(thr_id);
+                child.wait().await
+            }
+        };
+        spawner.reaped(thr_id);
+        match status {
+            Ok(status) if status.success() => debug!("Thread {} exited", thr_id),
+            Ok(status) => warn!("Thread {} exited with {}", thr_id, status),
+            Err(e) => error!("Unable to reap thread {}: {}", thr_id, e),
+        }
+
+        if matches!(ended, Ended::Finished) || *shared.stop.borrow() != Stop::Running {
+            return;
+        }
+        if respawns == max_respawns {
+            error!(
+                "Thread {} was lost {} times, not replacing it again",
+                thr_id,
+                respawns + 1
+            );
+            return;
+        }
+        respawns += 1;
+        warn!(
+            "Replacing thread {} ({}/{})",
+            thr_id, respawns, max_respawns
+        );
+    }
+}
+
+/// Reads a new connection's greeting and hands it to the slot that spawned the worker, turning away workers we
+/// can't talk to
+pub async fn greet(mut stream: UnixStream, arrivals: Arrivals, max_frame_size: usize) {
+    let greeting = time::timeout(
+        HEARTBEAT_TIMEOUT,
+        stream.read_json_msg::<Value>(max_frame_size),
+    )
+    .await
+    .context("Timed out")
+    .and_then(|greeting| greeting.context("Unable to read greeting, the peer may be speaking an older protocol"))
+    .and_then(comms::parse_greeting);
+    let greeting = match greeting {
+        Ok(greeting) => greeting,
+        Err(e) => {
+            error!("Dropping worker with an unusable greeting: {:#}", e);
+            let _ = stream
+                .write_json_msg(&Message::Rejected(format!("{:#}", e)))
+                .await;
+            return;
+        }
+    };
+    let thr_id = greeting.thr_id;
+    let Some(arrival) = arrivals.lock().unwrap().remove(&thr_id) else {
+        error!("Rejecting thread {}: no such thread was started", thr_id);
+        let _ = stream
+            .write_json_msg(&Message::Rejected(format!(
+                "unexpected thread ID {}",
+                thr_id
+            )))
+            .await;
+        return;
+    };
+    let unusable = greeting.incompatibility(&WORKER_ACCEPTS).or_else(|| {
+        greeting
+            .capabilities
+            .yt_dlp_error
+            .as_ref()
+            .map(|e| format!("unable to import yt-dlp: {}", e))
+    });
+    if let Some(reason) = unusable {
+        error!("Rejecting thread {}: {}", thr_id, reason);
+        let _ = stream
+            .write_json_msg(&Message::Rejected(reason.clone()))
+            .await;
+        let _ = arrival.send(Err(reason));
+        return;
+    }
+    let capabilities = &greeting.capabilities;
+    info!(
+        "Thread {} connected, yt-dlp {}",
+        thr_id,
+        capabilities.yt_dlp_version.as_deref().unwrap_or("unknown")
+    );
+    if !capabilities.ffmpeg {
+        warn!(
+            "Thread {} has no ffmpeg, separate video and audio streams won't be merged",
+            thr_id
+        );
+    }
+
+    let reply = Greeting::new(
+        thr_id,
+        Capabilities {
+            accepts: MASTER_ACCEPTS.map(str::to_owned).to_vec(),
+            ..Default::default()
+        },
+    );
+    if let Err(e) = stream.write_json_msg(&Message::Greeting(reply)).await {
+        error!("Unable to greet thread {}: {}", thr_id, e);
+        return;
+    }
+    let _ = arrival.send(Ok(stream));
+}
+
+/// Writes whatever is queued for a worker, so anything holding its outbox can push to it and not only in reply
+async fn write_messages(mut socket: OwnedWriteHalf, mut outbox: UnboundedReceiver<Message>, thr_id: usize) {
+    while let Some(msg) = outbox.recv().await {
+        if let Err(e) = socket.write_json_msg(&msg).await {
+            // The reader notices too and drops the worker
+            warn!("Unable to send {} to thread {}: {}", msg.kind(), thr_id, e);
+            return;
+        }
+    }
+}
+
+/// Sets aside a payload we couldn't use. The worker carries on, the video just won't be recorded until it's replayed
+fn quarantine(shared: &Shared, quarantined: Quarantined) -> Option<PathBuf> {
+    match quarantined.save(&quarantine::dir(&shared.logs_dir)) {
+        Ok(path) => {
+            quarantined.log(&path);
+            Some(path)
+        }
+        Err(e) => {
+            error!(
+                "Unable to quarantine {:?} from thread {}: {:#}",
+                quarantined.kind, quarantined.thr_id, e
+            );
+            None
+        }
+    }
+}
+
+/// Hands worker `thr_id` its next batch, unless the run is stopping or nothing is due
+fn take_batch(shared: &Shared, thr_id: usize) -> Result<Option<Vec<String>>> {
+    if *shared.stop.borrow() != Stop::Running {
+        return Ok(None);
+    }
+    queue::next_batch(
+        &mut shared.connection.lock().unwrap(),
+        shared.batch_size,
+        thr_id,
+    )
+}
+
+/// Queues the videos a playlist or channel expanded into and marks it done, returning how many were new
+fn queue_entries(shared: &Shared, source: &str, entries: Vec<PlaylistEntry>) -> Result<usize> {
+    let mut queued = 0;
+    let mut queue = shared.queue.lock().unwrap();
+    let conn = &mut *shared.connection.lock().unwrap();
+    for (i, e) in entries.into_iter().enumerate() {
+        let entry = SourceEntry {
+            link: e.url,
+            title: e.title,
+            channel: e.channel,
+            position: Some(i + 1),
+        };
+        if queue.push(conn, entry)? {
+            queued += 1;
+        }
+    }
+    queue::finish(conn, source, source, QueueStatus::Done, None)?;
+    Ok(queued)
+}
+
+/// Writes the log `rebuild-db` restores the library from, next to the run reports
+fn write_download_log(shared: &Shared, status: &DownloadStatus) {
+    let path = Path::new(&shared.logs_dir).join(status.log_name());
+    if let Err(e) = serde_json::to_vec_pretty(status)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(fs::write(&path, json)?))
+    {
+        error!("Unable to write {}: {:#}", path.display(), e);
+    }
+}
+
+/// Reports what `verdict` decided for a failed `link` and counts it in the run summary
+fn tally_failure(shared: &Shared, link: String, kind: comms::ErrorKind, message: String, attempts: u32, verdict: Verdict) {
+    let mut summary = shared.summary.lock().unwrap();
+    match verdict {
+        Verdict::Retry { retry_at } => {
+            warn!(
+                "Retrying {} in {}s (attempt {}/{})",
+                link,
+                (retry_at - queue::unix_now()).max(0),
+                attempts + 1,
+                shared.retry.max_attempts
+            );
+            summary.retried += 1;
+        }
+        Verdict::GiveUp | Verdict::Permanent => summary.failed.push(report::FailedLink {
+            link,
+            kind,
+            message,
+            permanent: verdict == Verdict::Permanent,
+        }),
+    }
+}
+
+/// Forgets a worker that died, hung or stopped because we're shutting down. The link a lost worker was on counts as a
+/// failed attempt, everything else it was holding goes back in line for the others, its replacement or the next run
+fn lose(shared: &Shared, batch: &[String], thr_id: usize, pb: &ProgressBar, reason: &str) -> Ended {
+    if *shared.stop.borrow() != Stop::Running {
+        info!("Thread {} stopped", thr_id);
+        pb.abandon_with_message(format!("Thread {} stopped", thr_id));
+        match queue::requeue_unfinished(&mut shared.connection.lock().unwrap(), batch, thr_id) {
+            Ok(0) => {}
+            Ok(requeued) => warn!("Requeued {} links thread {} was holding", requeued, thr_id),
+            Err(e) => error!("{:#}", e),
+        }
+        return Ended::Lost;
+    }
+
+    error!("Lost thread {}: {}", thr_id, reason);
+    pb.abandon_with_message(format!("Thread {} lost: {}", thr_id, reason));
+    let kind = comms::ErrorKind::Other;
+    let message = format!("worker lost: {}", reason);
+    let lost = queue::fail_lost(
+        &mut shared.connection.lock().unwrap(),
+        batch,
+        thr_id,
+        |link, attempts| shared.retry.verdict(link, kind, &message, attempts),
+        kind.as_str(),
+        &message,
+    );
+    match lost {
+        Ok(Some((link, attempts, verdict))) => tally_failure(shared, link, kind, message, attempts, verdict),
+        Ok(None) => {}
+        Err(e) => error!("{:#}", e),
+    }
+    Ended::Lost
+}
+
+async fn serve(stream: UnixStream, thr_id: usize, shared: &Shared) -> Ended {
+    let (mut reader, writer) = stream.into_split();
+    let (outbox, outbox_rx) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_messages(writer, outbox_rx, thr_id));
+
+    let pb = ProgressBar::new_spinner();
+    pb.set_length(10000);
+    pb.enable_steady_tick(Duration::from_millis(25));
+    shared.mp.lock().unwrap().add(pb.clone());
+
+    {
+        // Under the lock, so a pause can't slip in between the check and the insert
+        let mut controls = shared.controls.lock().unwrap();
+        if *shared.paused.borrow() {
+            let _ = outbox.send(Message::Pause);
+        }
+        controls.insert(thr_id, outbox.clone());
+    }
+    let ended = serve_messages(&mut reader, outbox, thr_id, shared, &pb).await;
+    // It holds a copy of the outbox, which keeps the writer going
+    shared.controls.lock().unwrap().remove(&thr_id);
+    match ended {
+        // Let the EndRequest out before the worker is waited on
+        Ended::Finished => {
+            let _ = writer.await;
+        }
+        // A hung worker may never read what's left
+        Ended::Lost => writer.abort(),
+    }
+    ended
+}
+
+async fn serve_messages(reader: &mut OwnedReadHalf, outbox: UnboundedSender<Message>, thr_id: usize, shared: &Shared, pb: &ProgressBar) -> Ended {
+    debug!("Thread {:?} functional", thr_id);
+    // Finished streams of the current download, turned into `files` rows once yt-dlp moves them into place
+    let mut audio_ds: Option<DownloadStatus> = None;
+    let mut video_ds: Option<DownloadStatus> = None;
+    // Info dict of the video being downloaded, progress ticks only carry its ID
+    let mut video_info: Option<InfoDict> = None;
+    // Where the current link's info went if it couldn't be used. Stays `Some` if saving it failed too
+    let mut info_quarantined: Option<Option<PathBuf>> = None;
+    // What the finished streams of the current link were recorded as, if any finished at all
+    let mut link_status: Option<QueueStatus> = None;
+    // Links handed to this worker in its last batch
+    let mut current_batch = Vec::<String>::new();
+    let mut last_seen = Instant::now();
+    // Last sign of progress on the current download, `None` between downloads
+    let mut last_activity: Option<Instant> = None;
+    // Link being downloaded and whether it was cancelled for making no progress
+    let mut current_link: Option<String> = None;
+    let mut stalled = false;
+    // A paused download makes no progress on purpose, so it isn't timed until it's resumed
+    let mut paused = shared.paused.subscribe();
+    // The DB or filesystem failing under a worker drops it like a broken connection would, so its links are requeued.
+    // Nothing may hold the connection when this returns, `lose` needs it
+    macro_rules! or_lose {
+        ($result:expr) => {{
+            let result = $result;
+            match result {
+                Ok(value) => value,
+                Err(e) => return lose(shared, &current_batch, thr_id, pb, &format!("{:#}", e)),
+            }
+        }};
+    }
+    loop {
+        if paused.has_changed().unwrap_or(false) && !*paused.borrow_and_update() && last_activity.is_some() {
+            last_activity = Some(Instant::now());
+        }
+        let deadline = match last_activity {
+            Some(activity) if !*paused.borrow() => (last_seen + HEARTBEAT_TIMEOUT).min(activity + shared.inactivity_timeout),
+            _ => last_seen + HEARTBEAT_TIMEOUT,
+        };
+        // Whatever was partially read when the deadline hits doesn't matter, the worker gets killed
+        let msg = match time::timeout_at(
+            deadline,
+            reader.read_json_msg::<Message>(shared.max_frame_size),
+        )
+        .await
+        {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(FrameError::Closed)) => return lose(shared, &current_batch, thr_id, pb, "disconnected"),
+            Ok(Err(e)) => return lose(shared, &current_batch, thr_id, pb, &e.to_string()),
+            // Paused or resumed while waiting, the deadline is worked out again
+            Err(_) if Instant::now() < last_seen + HEARTBEAT_TIMEOUT && paused.has_changed().unwrap_or(false) => continue,
+            Err(_) if Instant::now() < last_seen + HEARTBEAT_TIMEOUT => {
+                let reason = format!("no download progress for {:?}", shared.inactivity_timeout);
+                // The worker is fine, only its download is stuck. The cancel lands at yt-dlp's next progress tick, a
+                // download that never ticks again gets the worker killed once the timeout runs out a second time
+                match current_link.as_ref().filter(|_| !stalled) {
+                    Some(link) => {
+                        warn!("{} on thread {}, cancelling {}", reason, thr_id, link);
+                        let _ = outbox.send(Message::Cancel { link: link.clone() });
+                        stalled = true;
+                        last_activity = Some(Instant::now());
+                        continue;
+                    }
+                    None => return lose(shared, &current_batch, thr_id, pb, &reason),
+                }
+            }
+            Err(_) => {
+                let reason = format!("no heartbeat for {:?}", HEARTBEAT_TIMEOUT);
+                return lose(shared, &current_batch, thr_id, pb, &reason);
+            }
+        };
+        last_seen = Instant::now();
+        match &msg {
+            Message::DownloadStart { .. } => last_activity = Some(last_seen),
+            Message::DownloadResult { .. } => last_activity = None,
+            Message::Heartbeat => {}
+            _ => {
+                if last_activity.is_some() {
+                    last_activity = Some(last_seen);
+                }
+            }
+        }
+
+        match msg {
+            // Batch request
+            Message::BatchRequest => {
+                debug!("got BatchRequest from socket {:?}", thr_id);
+                let failed = or_lose!(queue::fail_unfinished(
+                    &mut shared.connection.lock().unwrap(),
+                    &current_batch,
+                    thr_id,
+                ));
+                if failed > 0 {
+                    warn!(
+                        "{} links from thread {} never finished downloading",
+                        failed, thr_id
+                    );
+                }
+                let mut batch = or_lose!(take_batch(shared, thr_id));
+                // Nothing to hand out right now, but links are waiting out a retry delay: hold the worker until the
+                // first of them is due rather than letting it go
+                while batch.is_none() && *shared.stop.borrow() == Stop::Running {
+                    let Some(retry_at) = or_lose!(queue::next_retry_at(&mut shared.connection.lock().unwrap())) else {
+                        break;
+                    };
+                    let wait = Duration::from_secs((retry_at - queue::unix_now()).max(0) as u64);
+                    debug!("Thread {} waiting {:?} for a retry", thr_id, wait);
+                    let mut stop = shared.stop.subscribe();
+                    tokio::select! {
+                        _ = time::sleep(wait) => {}
+                        _ = stop.wait_for(|s| *s != Stop::Running) => {}
+                    }
+                    // Its heartbeats pile up unread meanwhile
+                    last_seen = Instant::now();
+                    batch = or_lose!(take_batch(shared, thr_id));
+                }
+                current_batch = batch.clone().unwrap_or_default();
+                match batch {
+                    Some(batch) => {
+                        debug!("Sending Batch({:?}) to thread {:?}", batch, thr_id);
+                        // A failed send shows up as a broken read, which drops the worker
+                        let _ = outbox.send(Message::Batch(batch));
+                    }
+                    None => {
+                        debug!("No batches left, sending EndRequest to thread {:?}", thr_id);
+                        let _ = outbox.send(Message::EndRequest);
+                        return Ended::Finished;
+                    }
+                };
+            }
+
+            // Log
+            Message::Log {
+                thr_id: _,
+                level,
+                target,
+                msg,
+            } => {
+                debug!("got Log message from socket {:?}", thr_id);
+
+                log!(target: &target, level, "{}", msg);
+            }
+
+            Message::VideoInfo(msg) => {
+                let info: InfoDict = match serde_json::from_str(&msg) {
+                    Ok(info) => info,
+                    Err(e) => {
+                        info_quarantined = Some(quarantine(
+                            shared,
+                            Quarantined::from_parse_error(PayloadKind::VideoInfo, thr_id, &msg, &e),
+                        ));
+                        continue;
+                    }
+                };
+                let unknown = info.unknown_fields();
+                if !unknown.is_empty() {
+                    if shared.strict_schema {
+                        let error = format!("unknown fields {}", Vec::from_iter(unknown).join(", "));
+                        info_quarantined = Some(quarantine(
+                            shared,
+                            Quarantined::new(PayloadKind::VideoInfo, thr_id, &msg, error),
+                        ));
+                        continue;
+                    }
+                    let mut summary = shared.summary.lock().unwrap();
+                    let new: Vec<_> = unknown
+                        .into_iter()
+                        .filter(|f| summary.schema_drift.insert(f.clone()))
+                        .collect();
+                    if !new.is_empty() {
+                        warn!(
+                            "yt-dlp sent fields we don't know about, keeping them in `other`: {}",
+                            new.join(", ")
+                        );
+                    }
+                }
+                pb.set_message(format!(
+                    "{} - {} [{}]",
+                    info.creator.clone().unwrap_or(info.uploader.clone()),
+                    info.title.clone(),
+                    info.display_id.clone()
+                ));
+                video_info = Some(info);
+            }
+
+            Message::Progress(progress) => {
+                pb.set_length(
+                    progress
+                        .total_bytes
+                        .unwrap_or(progress.total_bytes_estimate.unwrap_or(0.0) as u64),
+                );
+                pb.set_position(progress.downloaded_bytes);
+
+                if progress.status != Status::Finished {
+                    continue;
+                }
+                let Some(info) = video_info.as_ref().filter(|i| i.id == progress.id) else {
+                    // A quarantined info was reported already
+                    if info_quarantined.is_none() {
+                        warn!(
+                            "Thread {} finished {} without sending its info, not recording it",
+                            thr_id, progress.filename
+                        );
+                    }
+                    continue;
+                };
+                let json = DownloadStatus::from_progress(&progress, info);
+                write_download_log(shared, &json);
+                match (
+                    json.info_dict.vcodec.as_str(),
+                    json.info_dict.acodec.as_str(),
+                ) {
+                    ("none", "none") => {}
+                    ("none", _) => audio_ds = Some(json.clone()),
+                    // Video-only stream or a single file with both
+                    _ => video_ds = Some(json.clone()),
+                }
+                let key = VideoKey::new(&json.info_dict.extractor_key, &json.info_dict.id).to_string();
+                let original_url = json.info_dict.original_url.clone();
+                if !json.info_dict.__real_download {
+                    or_lose!(queue::finish(
+                        &mut shared.connection.lock().unwrap(),
+                        &key,
+                        &original_url,
+                        QueueStatus::Skipped,
+                        Some("Already downloaded"),
+                    ));
+                    link_status.get_or_insert(QueueStatus::Skipped);
+                } else {
+                    let recorded = {
+                        let conn = &mut *shared.connection.lock().unwrap();
+                        library::record_video(conn, &json.info_dict).and_then(|recorded| {
+                            queue::finish(conn, &key, &original_url, QueueStatus::Done, None)?;
+                            Ok(recorded)
+                        })
+                    };
+                    if !or_lose!(recorded) {
+                        debug!("{} is already in the DB", key);
+                    }
+                    link_status = Some(QueueStatus::Done);
+                    shared.summary.lock().unwrap().bytes += progress.downloaded_bytes;
+                    pb.set_style(ProgressStyle::default_spinner());
+                }
+            }
+            Message::PlaylistEntries {
+                link: source,
+                entries,
+            } => {
+                let count = entries.len();
+                let queued = or_lose!(queue_entries(shared, &source, entries));
+                info!(
+                    "Expanded {} into {} entries, {} queued",
+                    source, count, queued
+                );
+            }
+            Message::FilesMoved(msg) => {
+                let moved: MovedFiles = match serde_json::from_str(&msg) {
+                    Ok(moved) => moved,
+                    Err(e) => {
+                        quarantine(
+                            shared,
+                            Quarantined::from_parse_error(PayloadKind::FilesMoved, thr_id, &msg, &e),
+                        );
+                        continue;
+                    }
+                };
+                // Its video isn't recorded until the info is replayed, the files go along with it
+                if info_quarantined.is_some() {
+                    let error = "the video's info was quarantined".to_owned();
+                    quarantine(
+                        shared,
+                        Quarantined::new(PayloadKind::FilesMoved, thr_id, &msg, error),
+                    );
+                    continue;
+                }
+                // Checksumming reads every file, which mustn't stall the runtime or keep the DB from the others
+                let (video, audio) = (video_ds.take(), audio_ds.take());
+                let (moved, downloaded) = or_lose!(
+                    tokio::task::spawn_blocking(move || {
+                        let downloaded = library::describe_files(&moved, video.as_ref(), audio.as_ref());
+                        (moved, downloaded)
+                    })
+                    .await
+                );
+                let recorded = or_lose!(library::insert_files(
+                    &mut shared.connection.lock().unwrap(),
+                    downloaded
+                ));
+                debug!("Recorded {} files for {}", recorded, moved.filepath);
+            }
+            Message::Heartbeat => {}
+            Message::DownloadStart { link } => {
+                pb.set_style(shared.pb_style.clone());
+                pb.tick();
+                link_status = None;
+                info_quarantined = None;
+                current_link = Some(link);
+                stalled = false;
+            }
+            Message::DownloadResult {
+                link,
+                outcome,
+                error_kind,
+                error_message,
+                files,
+            } => {
+                let key = VideoKey::normalize(&link)
+                    .map(|k| k.to_string())
+                    .unwrap_or(link.clone());
+                let was_stalled = stalled && current_link.as_ref() == Some(&link);
+                current_link = None;
+                stalled = false;
+                match outcome {
+                    Outcome::Failed => {
+                        let mut kind = error_kind.unwrap_or(comms::ErrorKind::Other);
+                        let mut message = error_message.unwrap_or_default();
+                        if kind == comms::ErrorKind::Cancelled {
+                            // Interrupted by a shutdown, it stays in progress until the worker disconnects and then
+                            // goes back in line
+                            if *shared.stop.borrow() == Stop::Killing {
+                                info!("Thread {} interrupted {}", thr_id, link);
+                                continue;
+                            }
+                            // We gave up on it, not the user, so it's retried like any other network failure
+                            if was_stalled {
+                                kind = comms::ErrorKind::Network;
+                                message = format!("no download progress for {:?}", shared.inactivity_timeout);
+                            }
+                        }
+                        if kind == comms::ErrorKind::Cancelled {
+                            warn!("Thread {} cancelled {}: {}", thr_id, link, message);
+                        } else {
+                            error!(
+                                "Thread {} failed {} ({}): {}",
+                                thr_id,
+                                link,
+                                kind.as_str(),
+                                message
+                            );
+                        }
+                        let failed = {
+                            let conn = &mut *shared.connection.lock().unwrap();
+                            queue::attempts(conn, &key, &link, thr_id).and_then(|attempts| {
+                                let verdict = shared.retry.verdict(&link, kind, &message, attempts);
+                                queue::fail(conn, &key, &link, thr_id, verdict, kind.as_str(), &message)?;
+                                Ok((attempts, verdict))
+                            })
+                        };
+                        let (attempts, verdict) = or_lose!(failed);
+                        tally_failure(shared, link, kind, message, attempts, verdict);
+                    }
+                    Outcome::Completed => {
+                        let mut status = link_status.take();
+                        if status.is_none() {
+                            // Downloaded, but the info it would be recorded with is waiting for a replay. Otherwise
+                            // e.g. filtered out by a match filter or archive, nothing reached the progress hook
+                            let (outcome, error) = match info_quarantined.take() {
+                                Some(path) => (
+                                    QueueStatus::Quarantined,
+                                    match path {
+                                        Some(path) => format!("info quarantined in {}", path.display()),
+                                        None => "info couldn't be read or quarantined".to_owned(),
+                                    },
+                                ),
+                                None => (QueueStatus::Skipped, "yt-dlp had nothing to download".to_owned()),
+                            };
+                            or_lose!(queue::finish(
+                                &mut shared.connection.lock().unwrap(),
+                                &key,
+                                &link,
+                                outcome,
+                                Some(&error),
+                            ));
+                            status = Some(outcome);
+                        }
+                        let mut summary = shared.summary.lock().unwrap();
+                        match status {
+                            Some(QueueStatus::Quarantined) => summary.quarantined.push(link.clone()),
+                            Some(QueueStatus::Done) if files.is_empty() => {
+                                warn!("Thread {} downloaded {} but reported no files", thr_id, link);
+                                summary.downloaded += 1;
+                                summary.without_files.push(link.clone());
+                            }
+                            Some(QueueStatus::Done) => summary.downloaded += 1,
+                            _ => summary.skipped += 1,
+                        }
+                        info!("Finished {} ({} files)", link, files.len());
+                    }
+                }
+            }
+            msg @ (Message::EndRequest
+            | Message::Batch(_)
+            | Message::Greeting(_)
+            | Message::Pause
+            | Message::Resume
+            | Message::Cancel { .. }
+            | Message::Shutdown { .. }) => {
+                // Only a confused worker sends these, it can't be trusted with the rest of its batch
+                return lose(shared, &current_batch, thr_id, pb, &format!("unexpected {}", msg.kind()));
+            }
+            Message::Rejected(reason) => {
+                error!("Thread {} hung up on us: {}", thr_id, reason);
+                return Ended::Finished;
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use rhytm::comms::{DownloadStatus, DrmState, InfoDict, LiveStatus, Status};
+use rhytm::links::VideoKey;
+use rhytm::models::NewVideo;
+use serde_json::Value;
+use std::{fs, path::Path};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+/// Names of the captures in `tests/fixtures`
+fn names() -> Vec<String> {
+    let mut names: Vec<_> = fs::read_dir(FIXTURES)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .map(|p| p.file_stem().unwrap().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// What yt-dlp sent for a video, as it sent it and as the master reads it
+struct Capture {
+    name: String,
+    raw: Value,
+    info: InfoDict,
+}
+
+/// Reads `tests/fixtures/<name>.json`, either a finished progress hook payload or a bare info dict as workers send it
+/// in `VideoInfo`. `what` says what to capture if it's missing
+fn load(name: &str, what: &str) -> Capture {
+    let path = Path::new(FIXTURES).join(format!("{}.json", name));
+    let Ok(bytes) = fs::read(&path) else {
+        panic!(
+            "No capture of {} in {}, make one with `python3 scripts/capture_fixture.py <URL> {}`, see the README there",
+            what, FIXTURES, name
+        );
+    };
+    let mut raw: Value = serde_json::from_slice(&bytes).unwrap();
+    let info = if raw.get("info_dict").is_some() {
+        let status: DownloadStatus =
+            serde_json::from_value(raw.clone()).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        assert_eq!(status.status, Status::Finished, "{}", name);
+        raw = raw["info_dict"].take();
+        status.info_dict
+    } else {
+        serde_json::from_value(raw.clone()).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    };
+    Capture {
+        name: name.to_owned(),
+        raw,
+        info,
+    }
+}
+
+/// Checks that `capture` is recorded with the key, link and title yt-dlp gave it and loses nothing on the way into
+/// `videos.other`, returns the row
+fn record(capture: &Capture) -> NewVideo {
+    let (name, raw) = (&capture.name, &capture.raw);
+    let video = NewVideo::from_info(&capture.info).unwrap();
+    assert_eq!(
+        video.uid,
+        VideoKey::new(
+            raw["extractor_key"].as_str().unwrap(),
+            raw["id"].as_str().unwrap()
+        )
+        .to_string(),
+        "{}",
+        name
+    );
+    assert_eq!(video.link.as_deref(), raw["webpage_url"].as_str(), "{}", name);
+    assert_eq!(video.title.as_deref(), raw["title"].as_str(), "{}", name);
+
+    // Every key yt-dlp sent comes back out of `videos.other` as it went in, known to the models or not
+    let stored: Value = serde_json::from_slice(video.other.as_deref().unwrap()).unwrap();
+    for (key, value) in raw.as_object().unwrap() {
+        assert_eq!(stored.get(key), Some(value), "{}: {}", name, key);
+    }
+    video
+}
+
+/// Checks what every video has, whatever else the case is about
+fn assert_dated(capture: &Capture, video: &NewVideo) {
+    let upload_date = capture.raw["upload_date"].as_str().unwrap();
+    assert_eq!(video.date, Some(upload_date.parse().unwrap()), "{}", capture.name);
+    assert!(video.duration.is_some_and(|d| d > 0), "{}", capture.name);
+}
+
+#[test]
+fn every_capture_is_recorded_whole() {
+    let names = names();
+    assert!(!names.is_empty(), "No captures in {}, see the README there", FIXTURES);
+    for name in names {
+        record(&load(&name, "anything"));
+    }
+}
+
+#[test]
+fn video() {
+    let capture = load("video", "a plain video, e.g. `-f worst`");
+    let video = record(&capture);
+    assert_dated(&capture, &video);
+    assert_ne!(capture.info.vcodec, "none");
+    assert_ne!(capture.info.acodec, "none");
+    assert_eq!(capture.info.live_status, LiveStatus::NotLive);
+    assert_eq!(capture.info.has_drm, DrmState::No);
+}
+
+#[test]
+fn audio_only() {
+    let capture = load("audio_only", "an audio-only download, e.g. `-f worstaudio`");
+    let video = record(&capture);
+    assert_dated(&capture, &video);
+    assert_eq!(capture.info.vcodec, "none");
+    assert_ne!(capture.info.acodec, "none");
+}
+
+#[test]
+fn hls() {
+    let capture = load("hls", "a download over HLS, e.g. `-f 'worst[protocol^=m3u8]'`");
+    let video = record(&capture);
+    assert_dated(&capture, &video);
+    assert!(capture.info.protocol.starts_with("m3u8"), "{}", capture.info.protocol);
+}
+
+#[test]
+fn live() {
+    let capture = load("live", "a finished live stream");
+    let video = record(&capture);
+    assert_dated(&capture, &video);
+    assert_eq!(capture.info.live_status, LiveStatus::WasLive);
+    assert!(capture.info.was_live && !capture.info.is_live);
+}
+
+#[test]
+fn age_restricted() {
+    let capture = load("age_restricted", "an age-restricted video, which takes `--cookies`");
+    let video = record(&capture);
+    assert_dated(&capture, &video);
+    assert!(capture.info.age_limit >= 18, "{}", capture.info.age_limit);
+}
+
+#[test]
+fn drm_maybe() {
+    let capture = load("drm_maybe", "a download yt-dlp couldn't tell is DRM free, i.e. `has_drm: \"maybe\"`");
+    record(&capture);
+    assert_eq!(capture.info.has_drm, DrmState::Maybe);
+    assert_eq!(capture.raw["has_drm"], "maybe");
+}
+
+#[test]
+fn playlist_entry() {
+    let capture = load("playlist_entry", "a playlist, of which the first video is downloaded");
+    let video = record(&capture);
+    assert_dated(&capture, &video);
+    // Keyed and linked as the video, not as the playlist it was found in
+    assert!(capture.info.playlist.is_some());
+    assert_eq!(capture.info.playlist_index, Some(1));
+    assert_eq!(VideoKey::normalize(video.link.as_deref().unwrap()).unwrap().to_string(), video.uid);
+}
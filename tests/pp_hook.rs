@@ -0,0 +1,91 @@
+use pyo3::{prelude::*, types::PyModule};
+use rhytm::comms::MovedFiles;
+use serde_json::{json, Value};
+
+/// Runs the worker's post-processor hook over `hooks`, returning what it reported and the files it collected
+fn run(hooks: &[Value]) -> (Vec<MovedFiles>, Vec<String>) {
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        let module = PyModule::from_code_bound(py, include_str!("../src/bin/pp_hook.py"), "pp_hook.py", "pp_hook").unwrap();
+        let reports = py.eval_bound("[]", None, None).unwrap();
+        module.setattr("pp_fn", reports.getattr("append").unwrap()).unwrap();
+
+        let json = py.import_bound("json").unwrap();
+        for hook in hooks {
+            let d = json.call_method1("loads", (hook.to_string(),)).unwrap();
+            module.call_method1("pp_hook", (d,)).unwrap();
+        }
+
+        let reports: Vec<String> = reports.extract().unwrap();
+        let files: Vec<String> = module.getattr("finished_files").unwrap().extract().unwrap();
+        (
+            reports
+                .iter()
+                .map(|r| serde_json::from_str(r).unwrap())
+                .collect(),
+            files,
+        )
+    })
+}
+
+/// A post-processor hook call, shaped like yt-dlp's `PostProcessor.run` wrapper sends it
+fn hook(status: &str, postprocessor: &str, info_dict: Value) -> Value {
+    json!({"status": status, "postprocessor": postprocessor, "info_dict": info_dict})
+}
+
+#[test]
+fn merged_download() {
+    let temp = json!({
+        "id": "Xq1Hm3t7bKc",
+        "extractor_key": "Youtube",
+        "format_id": "248+251",
+        "ext": "webm",
+        "vcodec": "vp9",
+        "acodec": "opus",
+        "filepath": "/tmp/rhytm/Harbour Lights [Xq1Hm3t7bKc].webm",
+        "__finaldir": "/downloads",
+        "__files_to_move": {
+            "/tmp/rhytm/Harbour Lights [Xq1Hm3t7bKc].webp": null,
+            "/tmp/rhytm/Harbour Lights [Xq1Hm3t7bKc].en.vtt": null,
+        },
+    });
+    // MoveFilesAfterDownload adds the download itself to the files to move and points `filepath` at its final place
+    let mut moved = temp.clone();
+    moved["filepath"] = json!("/downloads/Harbour Lights [Xq1Hm3t7bKc].webm");
+    moved["__files_to_move"]["/tmp/rhytm/Harbour Lights [Xq1Hm3t7bKc].webm"] = json!("/downloads/Harbour Lights [Xq1Hm3t7bKc].webm");
+
+    let (reports, files) = run(&[
+        hook("started", "FFmpegMerger", temp.clone()),
+        hook("finished", "FFmpegMerger", temp.clone()),
+        hook("started", "MoveFilesAfterDownload", temp),
+        hook("finished", "MoveFilesAfterDownload", moved),
+    ]);
+
+    assert_eq!(reports.len(), 1);
+    let report = &reports[0];
+    assert_eq!(report.id, "Xq1Hm3t7bKc");
+    assert_eq!(report.extractor_key, "Youtube");
+    assert_eq!(report.filepath, "/downloads/Harbour Lights [Xq1Hm3t7bKc].webm");
+    assert_eq!(report.format_id.as_deref(), Some("248+251"));
+    assert_eq!(
+        report.moved["/tmp/rhytm/Harbour Lights [Xq1Hm3t7bKc].en.vtt"],
+        "/downloads/Harbour Lights [Xq1Hm3t7bKc].en.vtt"
+    );
+    assert_eq!(report.moved.len(), 3);
+
+    assert_eq!(files[0], "/downloads/Harbour Lights [Xq1Hm3t7bKc].webm");
+    assert!(files.contains(&"/downloads/Harbour Lights [Xq1Hm3t7bKc].webp".to_owned()));
+    assert!(files.contains(&"/downloads/Harbour Lights [Xq1Hm3t7bKc].en.vtt".to_owned()));
+}
+
+#[test]
+fn other_postprocessors_are_ignored() {
+    let info = json!({"id": "x", "extractor_key": "Youtube", "filepath": "/downloads/x.webm"});
+    let (reports, files) = run(&[
+        hook("finished", "FFmpegMetadata", info.clone()),
+        hook("finished", "EmbedThumbnail", info.clone()),
+        hook("finished", "MoveFiles", info),
+    ]);
+    assert!(reports.is_empty());
+    assert!(files.is_empty());
+}
@@ -0,0 +1,244 @@
+use diesel::{prelude::*, sqlite::SqliteConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use rhytm::comms::{Chapter, DownloadStatus, InfoDict, Status};
+use rhytm::models::{NewChannel, NewVideo};
+use rhytm::rebuild;
+use rhytm::schema::{categories, channels, chapters, files, video_tags, videos};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+const UID: &str = "youtube Xq1Hm3t7bKc";
+
+fn library() -> SqliteConnection {
+    let mut conn = SqliteConnection::establish(":memory:").unwrap();
+    conn.run_pending_migrations(MIGRATIONS).unwrap();
+    conn
+}
+
+/// An empty logs dir of its own for `test`, with the downloaded file it's about
+fn logs_dir(test: &str) -> (PathBuf, String) {
+    let dir = std::env::temp_dir().join(format!("rhytm-rebuild-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let download = dir.join("Harbour Lights [Xq1Hm3t7bKc].opus");
+    fs::write(&download, b"not really opus").unwrap();
+    (dir, download.to_string_lossy().into_owned())
+}
+
+/// Logs a finished audio download of the video as the master does, extracted at `epoch`
+fn log(dir: &Path, download: &str, epoch: u64, format_id: &str, title: &str, description: &str) {
+    write_log(dir, &status(download, epoch, format_id, title, description));
+}
+
+fn write_log(dir: &Path, status: &DownloadStatus) {
+    fs::write(dir.join(status.log_name()), serde_json::to_vec(status).unwrap()).unwrap();
+}
+
+/// A finished audio download of the video, extracted at `epoch`
+fn status(download: &str, epoch: u64, format_id: &str, title: &str, description: &str) -> DownloadStatus {
+    DownloadStatus {
+        status: Status::Finished,
+        filename: download.to_owned(),
+        info_dict: InfoDict {
+            id: "Xq1Hm3t7bKc".to_owned(),
+            extractor_key: "Youtube".to_owned(),
+            webpage_url: "https://www.youtube.com/watch?v=Xq1Hm3t7bKc".to_owned(),
+            title: title.to_owned(),
+            description: description.to_owned(),
+            epoch,
+            format_id: format_id.to_owned(),
+            vcodec: "none".to_owned(),
+            acodec: "opus".to_owned(),
+            filename: download.to_owned(),
+            channel_id: "UCharbour".to_owned(),
+            channel: "Harbour".to_owned(),
+            tags: vec!["harbour".to_owned(), "lights".to_owned()],
+            categories: vec!["Music".to_owned()],
+            chapters: Some(vec![
+                Chapter {
+                    title: "Intro".to_owned(),
+                    start_time: 0.0,
+                    end_time: 30.0,
+                    extra: Default::default(),
+                },
+                Chapter {
+                    title: "Song".to_owned(),
+                    start_time: 30.0,
+                    end_time: 240.0,
+                    extra: Default::default(),
+                },
+            ]),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// A row for the video with nothing but its title, as recorded before the metadata tables existed
+fn bare_video(conn: &mut SqliteConnection) {
+    diesel::insert_into(videos::table)
+        .values(NewVideo {
+            uid: UID.to_owned(),
+            link: None,
+            title: Some("Harbour lights".to_owned()),
+            author: None,
+            duration: None,
+            description: None,
+            thumbnail_path: None,
+            date: None,
+            other: None,
+            channel_id: None,
+            album_id: None,
+            track: None,
+        })
+        .execute(conn)
+        .unwrap();
+}
+
+/// Rows in `table`
+macro_rules! count {
+    ($conn:expr, $table:expr) => {
+        $table.count().get_result::<i64>($conn).unwrap()
+    };
+}
+
+#[test]
+fn fresh() {
+    let (dir, download) = logs_dir("fresh");
+    log(&dir, &download, 300, "251", "Harbour Lights", "Live at the pier");
+    log(&dir, &download, 200, "250", "Harbour Lights (live)", "Live at the pier");
+    log(&dir, &download, 100, "249", "Harbour Lights (live)", "Recorded at the pier");
+    fs::write(dir.join("broken.json"), b"{").unwrap();
+    let mut conn = library();
+
+    let rebuilt = rebuild::rebuild(&mut conn, &dir).unwrap();
+    assert_eq!((rebuilt.logs, rebuilt.unreadable), (3, 1));
+    assert_eq!((rebuilt.inserted, rebuilt.repaired, rebuilt.files), (1, 0, 1));
+
+    let (title, channel_id): (Option<String>, Option<i64>) = videos::table
+        .filter(videos::uid.eq(UID))
+        .select((videos::title, videos::channel_id))
+        .first(&mut conn)
+        .unwrap();
+    assert_eq!(title.as_deref(), Some("Harbour Lights"));
+    assert!(channel_id.is_some());
+    assert_eq!(count!(&mut conn, video_tags::table), 2);
+    assert_eq!(count!(&mut conn, categories::table), 1);
+    assert_eq!(count!(&mut conn, chapters::table), 2);
+    assert_eq!(count!(&mut conn, files::table), 1);
+
+    // Two older logs agreeing on a title are one conflict, not two
+    let mut conflicts: Vec<_> = rebuilt
+        .conflicts
+        .iter()
+        .map(|c| (c.uid.as_str(), c.field, c.kept.as_str(), c.ignored.clone()))
+        .collect();
+    conflicts.sort();
+    assert_eq!(
+        conflicts,
+        [
+            (UID, "description", "Live at the pier", vec!["Recorded at the pier".to_owned()]),
+            (UID, "title", "Harbour Lights", vec!["Harbour Lights (live)".to_owned()]),
+        ]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn repair() {
+    let (dir, download) = logs_dir("repair");
+    log(&dir, &download, 200, "251", "Harbour Lights", "Live at the pier");
+    log(&dir, &download, 100, "250", "Harbour Lights (live)", "Live at the pier");
+    let mut conn = library();
+    bare_video(&mut conn);
+
+    let rebuilt = rebuild::rebuild(&mut conn, &dir).unwrap();
+    assert_eq!((rebuilt.inserted, rebuilt.repaired), (0, 1));
+
+    let (title, description, channel_id): (Option<String>, Option<String>, Option<i64>) = videos::table
+        .filter(videos::uid.eq(UID))
+        .select((videos::title, videos::description, videos::channel_id))
+        .first(&mut conn)
+        .unwrap();
+    assert_eq!(title.as_deref(), Some("Harbour lights"));
+    assert_eq!(description.as_deref(), Some("Live at the pier"));
+    assert!(channel_id.is_some());
+    assert_eq!(count!(&mut conn, video_tags::table), 2);
+    assert_eq!(count!(&mut conn, categories::table), 1);
+    assert_eq!(count!(&mut conn, chapters::table), 2);
+    assert_eq!(count!(&mut conn, files::table), 1);
+
+    // The row wins over every log, however many disagree with it
+    assert_eq!(rebuilt.conflicts.len(), 1);
+    let conflict = &rebuilt.conflicts[0];
+    assert_eq!((conflict.field, conflict.kept.as_str()), ("title", "Harbour lights"));
+    assert_eq!(conflict.ignored, ["Harbour Lights", "Harbour Lights (live)"]);
+
+    // Nothing left to repair the second time around, and nothing doubled up
+    let rebuilt = rebuild::rebuild(&mut conn, &dir).unwrap();
+    assert_eq!((rebuilt.inserted, rebuilt.repaired), (0, 0));
+    assert_eq!(count!(&mut conn, video_tags::table), 2);
+    assert_eq!(count!(&mut conn, chapters::table), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn channels_are_left_alone() {
+    let (dir, download) = logs_dir("channels");
+    log(&dir, &download, 200, "251", "Harbour Lights", "Live at the pier");
+    let mut conn = library();
+    bare_video(&mut conn);
+    // Renamed by a download newer than the logs
+    diesel::insert_into(channels::table)
+        .values(NewChannel {
+            uid: "UCharbour".to_owned(),
+            name: Some("Harbour Band".to_owned()),
+            url: None,
+            follower_count: Some(1000),
+            verified: true,
+        })
+        .execute(&mut conn)
+        .unwrap();
+
+    let rebuilt = rebuild::rebuild(&mut conn, &dir).unwrap();
+    assert_eq!(rebuilt.repaired, 1);
+    let (name, follower_count, verified): (Option<String>, Option<i64>, bool) = channels::table
+        .select((channels::name, channels::follower_count, channels::verified))
+        .first(&mut conn)
+        .unwrap();
+    assert_eq!((name.as_deref(), follower_count, verified), (Some("Harbour Band"), Some(1000), true));
+    let channel_id: Option<i64> = videos::table.select(videos::channel_id).first(&mut conn).unwrap();
+    assert!(channel_id.is_some());
+
+    let conflict = rebuilt.conflicts.iter().find(|c| c.field == "channels.name").unwrap();
+    assert_eq!((conflict.uid.as_str(), conflict.kept.as_str()), ("UCharbour", "Harbour Band"));
+    assert_eq!(conflict.ignored, ["Harbour"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failed_videos_dont_stop_the_rest() {
+    let (dir, download) = logs_dir("failed");
+    log(&dir, &download, 100, "251", "Harbour Lights", "Live at the pier");
+    let mut broken = status(&download, 100, "251", "Tide", "Live at the pier");
+    broken.info_dict.id = "TideTideTid".to_owned();
+    broken.info_dict.chapters.as_mut().unwrap()[1].title = "Boom".to_owned();
+    write_log(&dir, &broken);
+    let mut conn = library();
+    diesel::sql_query(
+        "CREATE TRIGGER boom BEFORE INSERT ON chapters WHEN NEW.title = 'Boom' BEGIN SELECT RAISE(ABORT, 'boom'); END",
+    )
+    .execute(&mut conn)
+    .unwrap();
+
+    let rebuilt = rebuild::rebuild(&mut conn, &dir).unwrap();
+    assert_eq!((rebuilt.inserted, rebuilt.failed, rebuilt.files), (1, 1, 1));
+    // Nothing of the failed one is left behind
+    let uids: Vec<String> = videos::table.select(videos::uid).load(&mut conn).unwrap();
+    assert_eq!(uids, [UID]);
+    assert_eq!(count!(&mut conn, chapters::table), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,100 @@
+use rhytm::comms::{Availability, DownloadStatus, DrmState, InfoDict, LiveStatus, Status, SubtitleTrack, Subtitles};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::fmt::Debug;
+
+/// Checks that `json` reads as `expected` and writes back as the same JSON
+fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(json: Value, expected: T) {
+    let parsed: T = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(parsed, expected);
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+}
+
+#[test]
+fn status() {
+    round_trip(json!("downloading"), Status::Downloading);
+    round_trip(json!("finished"), Status::Finished);
+    round_trip(json!("error"), Status::Error);
+    assert!(serde_json::from_value::<Status>(json!("Finished")).is_err());
+    assert_eq!(Status::parse("finished"), Some(Status::Finished));
+    assert_eq!(Status::parse("started"), None);
+}
+
+#[test]
+fn drm_state() {
+    round_trip(json!(true), DrmState::Yes);
+    round_trip(json!(false), DrmState::No);
+    round_trip(json!("maybe"), DrmState::Maybe);
+    assert!(serde_json::from_value::<DrmState>(json!("yes")).is_err());
+    assert!(serde_json::from_value::<DrmState>(json!(1)).is_err());
+}
+
+#[test]
+fn live_status() {
+    round_trip(json!("not_live"), LiveStatus::NotLive);
+    round_trip(json!("is_live"), LiveStatus::IsLive);
+    round_trip(json!("is_upcoming"), LiveStatus::IsUpcoming);
+    round_trip(json!("was_live"), LiveStatus::WasLive);
+    round_trip(json!("post_live"), LiveStatus::PostLive);
+    round_trip(json!("is_rerun"), LiveStatus::Other("is_rerun".to_owned()));
+}
+
+#[test]
+fn availability() {
+    round_trip(json!("public"), Availability::Public);
+    round_trip(json!("unlisted"), Availability::Unlisted);
+    round_trip(json!("private"), Availability::Private);
+    round_trip(json!("premium_only"), Availability::PremiumOnly);
+    round_trip(json!("subscriber_only"), Availability::SubscriberOnly);
+    round_trip(json!("needs_auth"), Availability::NeedsAuth);
+    round_trip(
+        json!("members_only"),
+        Availability::Other("members_only".to_owned()),
+    );
+}
+
+#[test]
+fn subtitles() {
+    let json = json!({
+        "en": [
+            {"ext": "vtt", "url": "https://example.com/en.vtt", "name": "English"},
+            {"ext": "srv3", "url": "https://example.com/en.srv3", "name": "English", "impersonate": true},
+        ],
+        "de": [{"ext": "vtt", "data": "WEBVTT\n"}],
+    });
+    let parsed: Subtitles = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(parsed["en"].len(), 2);
+    assert_eq!(parsed["en"][0].ext.as_deref(), Some("vtt"));
+    assert_eq!(parsed["en"][1].extra["impersonate"], json!(true));
+    assert_eq!(parsed["de"][0].url, None);
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+}
+
+#[test]
+fn info_dict() {
+    let mut json = serde_json::to_value(InfoDict::default()).unwrap();
+    json["has_drm"] = json!("maybe");
+    json["live_status"] = json!("post_live");
+    json["availability"] = json!("needs_auth");
+    json["release_timestamp"] = json!(1700000000);
+    json["subtitles"] = json!({"en": [{"ext": "vtt", "url": "https://example.com/en.vtt", "name": "English"}]});
+    json["requested_subtitles"] = json!({"en": {"ext": "vtt", "url": "https://example.com/en.vtt", "name": "English", "filepath": "/tmp/x.en.vtt"}});
+
+    let info: InfoDict = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(info.has_drm, DrmState::Maybe);
+    assert_eq!(info.live_status, LiveStatus::PostLive);
+    assert_eq!(info.availability, Availability::NeedsAuth);
+    assert_eq!(info.release_timestamp, Some(1700000000));
+    let requested: &SubtitleTrack = &info.requested_subtitles.as_ref().unwrap()["en"];
+    assert_eq!(requested.extra["filepath"], json!("/tmp/x.en.vtt"));
+    assert_eq!(serde_json::to_value(&info).unwrap(), json);
+}
+
+#[test]
+fn download_status() {
+    let mut json = serde_json::to_value(DownloadStatus::default()).unwrap();
+    json["status"] = json!("finished");
+    let status: DownloadStatus = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(status.status, Status::Finished);
+    assert_eq!(serde_json::to_value(&status).unwrap(), json);
+}